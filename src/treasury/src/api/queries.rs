@@ -1,12 +1,605 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap };
+
+use candid::{ CandidType, Principal };
 use ic_cdk::query;
+use icrc_ledger_types::icrc1::transfer::BlockIndex;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
 
-use crate::TRANSFER_HISTORY;
+use crate::{
+    schema_version,
+    BATCH_RUNS,
+    BUDGETS,
+    CLAIMS,
+    CONDITIONAL_TRANSFERS,
+    FEE_HISTORY,
+    LEDGER_CONFIGS,
+    OBSERVERS,
+    QUARANTINED_TRANSFERS,
+    RECURRING_TRANSFERS,
+    SAFE_RECIPIENT_CANISTERS,
+    PERMITTED_LEDGERS,
+    RECENT_ERRORS,
+    SCHEDULED_TRANSFERS,
+    SIGNER_HEARTBEATS,
+    HISTORY_ARCHIVES,
+    TRANSFER_HISTORY,
+};
 
-use super::updates::TransferHistory;
+use super::updates::{
+    active_timers,
+    archived_range_for,
+    check_read_access,
+    budget_period_start,
+    category_spent,
+    estimated_safe_batch_size,
+    explorer_url,
+    ledger_config,
+    lifetime_outflow,
+    timers_match_persisted_jobs,
+    ledger_latency,
+    ArchivedRange,
+    ledger_stats,
+    treasury_config,
+    BatchRun,
+    BatchRunId,
+    Budget,
+    BudgetId,
+    Claim,
+    ConditionalTransferId,
+    ConditionalTransferRecord,
+    ErrorRecord,
+    HistoryEntry,
+    LatencyStats,
+    LedgerConfig,
+    LedgerStats,
+    QuarantineId,
+    QuarantinedTransfer,
+    RecurringId,
+    RecurringTransfer,
+    ScheduleId,
+    ScheduledTransfer,
+    TimerInfo,
+    TrackingId,
+    TransferHistory,
+    TransferStatus,
+    TreasuryConfig,
+};
 
-#[query]
+const MAX_EXPORT_LIMIT: u64 = 100;
+const MAX_QUERY_HISTORY_RESULTS: usize = 500;
+const MAX_RECENT_ERRORS: u64 = 100;
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct CallerStats {
+    pub principal: Principal,
+    pub transfer_count: u64,
+    // Summed across ledgers, so only meaningful per ledger when a single token is used.
+    pub total_amount: u64,
+    pub last_active: u64,
+}
+
+// Every set field must match. Amounts are compared against the total of all legs of
+// an entry, and recipient matches if any leg pays it.
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct HistoryFilter {
+    pub ledger_id: Option<Principal>,
+    pub recipient: Option<Principal>,
+    pub from_ns: Option<u64>,
+    pub to_ns: Option<u64>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    pub initiated_by: Option<Principal>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        if self.ledger_id.is_some_and(|ledger_id| entry.transfer.ledger_id() != ledger_id) {
+            return false;
+        }
+        if self.initiated_by.is_some() && entry.caller != self.initiated_by {
+            return false;
+        }
+
+        // Entries recorded before timestamps existed cannot satisfy a time bound.
+        if self.from_ns.is_some() || self.to_ns.is_some() {
+            let Some(timestamp) = entry.timestamp else {
+                return false;
+            };
+            if self.from_ns.is_some_and(|from_ns| timestamp < from_ns) {
+                return false;
+            }
+            if self.to_ns.is_some_and(|to_ns| timestamp > to_ns) {
+                return false;
+            }
+        }
+
+        let legs = entry.transfer.legs();
+        let pays_recipient = |recipient: Principal| {
+            legs.iter().any(|leg| leg.receiving_principal == recipient)
+        };
+        if self.recipient.is_some_and(|recipient| !pays_recipient(recipient)) {
+            return false;
+        }
+        let amount = entry
+            .paid_legs()
+            .iter()
+            .fold(0u64, |total, leg| total.saturating_add(leg.amount));
+        if self.min_amount.is_some_and(|min_amount| amount < min_amount) {
+            return false;
+        }
+        if self.max_amount.is_some_and(|max_amount| amount > max_amount) {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct LifetimeOutflow {
+    pub cap: Option<u64>,
+    pub transferred: u64,
+    pub remaining: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct BudgetStatus {
+    pub id: BudgetId,
+    pub budget: Budget,
+    pub period_start_ns: u64,
+    pub spent: u64,
+    pub remaining: u64,
+}
+
+#[query(guard = "check_read_access")]
+pub fn list_observers() -> Vec<Principal> {
+    OBSERVERS.with(|observers| {
+        observers
+            .borrow()
+            .iter()
+            .map(|(observer, _)| observer)
+            .collect::<Vec<Principal>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
 pub fn get_transfer_history() -> Vec<TransferHistory> {
     TRANSFER_HISTORY.with(|history| {
-        history.borrow().iter().map(|(_, v)| v.clone()).collect::<Vec<TransferHistory>>()
+        history.borrow().iter().map(|(_, v)| v.transfer).collect::<Vec<TransferHistory>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_ledger_config(ledger_id: Principal) -> Option<LedgerConfig> {
+    LEDGER_CONFIGS.with(|configs| configs.borrow().get(&ledger_id))
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_recurring_transfers() -> Vec<(RecurringId, RecurringTransfer)> {
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers.borrow().iter().collect::<Vec<(RecurringId, RecurringTransfer)>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_recurring_executions(recurring_id: RecurringId) -> Vec<(u64, TransferHistory)> {
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.recurring_id == Some(recurring_id))
+            .map(|(id, entry)| (id, entry.transfer))
+            .collect::<Vec<(u64, TransferHistory)>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_scheduled_transfers() -> Vec<(ScheduleId, ScheduledTransfer)> {
+    SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        scheduled_transfers.borrow().iter().collect::<Vec<(ScheduleId, ScheduledTransfer)>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_treasury_config() -> TreasuryConfig {
+    treasury_config()
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_safe_recipient_canisters() -> Vec<Principal> {
+    SAFE_RECIPIENT_CANISTERS.with(|canisters| {
+        canisters
+            .borrow()
+            .iter()
+            .map(|(canister_id, _)| canister_id)
+            .collect::<Vec<Principal>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_permitted_ledgers() -> Vec<Principal> {
+    PERMITTED_LEDGERS.with(|ledgers| {
+        ledgers
+            .borrow()
+            .iter()
+            .map(|(ledger_id, _)| ledger_id)
+            .collect::<Vec<Principal>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_batch_runs() -> Vec<(BatchRunId, BatchRun)> {
+    BATCH_RUNS.with(|runs| runs.borrow().iter().collect::<Vec<(BatchRunId, BatchRun)>>())
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_quarantined_transfers() -> Vec<(QuarantineId, QuarantinedTransfer)> {
+    QUARANTINED_TRANSFERS.with(|quarantined| {
+        quarantined.borrow().iter().collect::<Vec<(QuarantineId, QuarantinedTransfer)>>()
+    })
+}
+
+// Looks in local history first and falls back to the archive canister holding the id.
+#[query(composite = true, guard = "check_read_access")]
+pub async fn get_history_entry(id: u64) -> Result<HistoryEntry, String> {
+    if let Some(entry) = TRANSFER_HISTORY.with(|history| history.borrow().get(&id)) {
+        return Ok(entry);
+    }
+    let range = archived_range_for(id).ok_or(format!("History entry {} not found", id))?;
+    let (entry,) = ic_cdk
+        ::call::<(u64,), (Option<HistoryEntry>,)>(range.canister_id, "get_history_entry", (id,)).await
+        .map_err(|e| format!("failed to call archive {}: {:?}", range.canister_id, e))?;
+    entry.ok_or(format!("History entry {} not found in archive {}", id, range.canister_id))
+}
+
+// (first_id, range) for every batch moved to an archive canister, oldest first.
+#[query(guard = "check_read_access")]
+pub fn get_history_archives() -> Vec<(u64, ArchivedRange)> {
+    HISTORY_ARCHIVES.with(|archives| archives.borrow().iter().collect())
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_oldest_transfer() -> Option<(u64, TransferHistory)> {
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .next()
+            .map(|(id, entry)| (id, entry.transfer))
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn export_history_json(start_id: u64, limit: u64) -> String {
+    let records = TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(start_id..)
+            .take(limit.min(MAX_EXPORT_LIMIT) as usize)
+            .map(|(id, entry)| history_entry_json(id, &entry))
+            .collect::<Vec<Value>>()
+    });
+    Value::Array(records).to_string()
+}
+
+// Returns up to MAX_EXPORT_LIMIT records starting at the continuation id (or the
+// oldest record) and the id to resume from, or None once the export is complete.
+#[query(guard = "check_read_access")]
+pub fn export_history_chunk(continuation: Option<u64>) -> (String, Option<u64>) {
+    TRANSFER_HISTORY.with(|history| {
+        let history = history.borrow();
+        let mut entries = history.range(continuation.unwrap_or_default()..);
+        let records = entries
+            .by_ref()
+            .take(MAX_EXPORT_LIMIT as usize)
+            .map(|(id, entry)| history_entry_json(id, &entry))
+            .collect::<Vec<Value>>();
+        let next = entries.next().map(|(id, _)| id);
+        (Value::Array(records).to_string(), next)
+    })
+}
+
+// Block indices are rendered as strings since they may exceed the range JSON
+// consumers can represent exactly.
+fn history_entry_json(id: u64, entry: &HistoryEntry) -> Value {
+    json!({
+        "id": id,
+        "transfer": entry.transfer,
+        "timestamp": entry.timestamp,
+        "caller": entry.caller,
+        "block_indices": entry.block_indices.as_ref().map(|indices| {
+            indices.iter().map(|index| index.0.to_string()).collect::<Vec<String>>()
+        }),
+        "explorer_urls": entry.block_indices.as_ref().map(|indices| {
+            indices
+                .iter()
+                .filter_map(|index| explorer_url(entry.transfer.ledger_id(), index))
+                .collect::<Vec<String>>()
+        }),
+        "recurring_id": entry.recurring_id,
+        "ledger_symbol": entry.ledger_symbol,
+        "fee_corrected": entry.fee_corrected,
+        "original_fee": entry.original_fee.as_ref().map(|fee| fee.0.to_string()),
+        "actual_fee": entry.actual_fee.as_ref().map(|fee| fee.0.to_string()),
+        "duplicates": entry.duplicates.as_ref().map(|duplicates| {
+            duplicates
+                .iter()
+                .map(|duplicate| {
+                    json!({
+                        "receiving_principal": duplicate.receiving_principal,
+                        "duplicate_of": duplicate.duplicate_of.0.to_string(),
+                        "handling": duplicate.handling,
+                    })
+                })
+                .collect::<Vec<Value>>()
+        }),
+    })
+}
+
+// Scans the full history; pairs are reported once, lower id first.
+#[query(guard = "check_read_access")]
+pub fn find_potential_duplicates(window_ns: u64) -> Vec<(u64, u64)> {
+    let entries = TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .filter_map(|(id, entry)| {
+                entry.timestamp.map(|timestamp| (id, timestamp, entry.transfer))
+            })
+            .collect::<Vec<(u64, u64, TransferHistory)>>()
+    });
+
+    let mut pairs = Vec::new();
+    for (index, (id, timestamp, transfer)) in entries.iter().enumerate() {
+        let legs = transfer.legs();
+        for (other_id, other_timestamp, other_transfer) in &entries[index + 1..] {
+            if other_timestamp.saturating_sub(*timestamp) > window_ns {
+                break;
+            }
+            if other_transfer.ledger_id() != transfer.ledger_id() {
+                continue;
+            }
+
+            let is_duplicate = other_transfer
+                .legs()
+                .iter()
+                .any(|other| {
+                    legs.iter().any(|leg| {
+                        leg.receiving_principal == other.receiving_principal &&
+                            leg.amount == other.amount
+                    })
+                });
+            if is_duplicate {
+                pairs.push((*id, *other_id));
+            }
+        }
+    }
+    pairs
+}
+
+#[query]
+pub fn get_safe_batch_size() -> u64 {
+    estimated_safe_batch_size()
+}
+
+// Computed from history; entries recorded before callers were tracked are skipped.
+#[query(guard = "check_read_access")]
+pub fn get_caller_stats() -> Vec<CallerStats> {
+    let mut stats: HashMap<Principal, CallerStats> = HashMap::new();
+    TRANSFER_HISTORY.with(|history| {
+        for (_, entry) in history.borrow().iter() {
+            let Some(caller) = entry.caller else {
+                continue;
+            };
+            let amount = entry
+                .paid_legs()
+                .iter()
+                .fold(0u64, |total, leg| total.saturating_add(leg.amount));
+
+            let caller_stats = stats.entry(caller).or_insert(CallerStats {
+                principal: caller,
+                transfer_count: 0,
+                total_amount: 0,
+                last_active: 0,
+            });
+            caller_stats.transfer_count += 1;
+            caller_stats.total_amount = caller_stats.total_amount.saturating_add(amount);
+            caller_stats.last_active = caller_stats.last_active.max(entry.timestamp.unwrap_or_default());
+        }
+    });
+
+    let mut stats = stats.into_values().collect::<Vec<CallerStats>>();
+    stats.sort_by(|a, b| {
+        b.transfer_count.cmp(&a.transfer_count).then(b.last_active.cmp(&a.last_active))
+    });
+    stats
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_budget_status() -> Vec<BudgetStatus> {
+    let budgets = BUDGETS.with(|budgets| budgets.borrow().iter().collect::<Vec<(BudgetId, Budget)>>());
+    budgets
+        .into_iter()
+        .map(|(id, budget)| {
+            let period_start_ns = budget_period_start(&budget);
+            let spent = category_spent(&budget.category, budget.ledger_id, period_start_ns);
+            BudgetStatus {
+                id,
+                remaining: budget.amount.saturating_sub(spent),
+                budget,
+                period_start_ns,
+                spent,
+            }
+        })
+        .collect()
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_fee_history(ledger_id: Principal) -> Vec<(u64, u64)> {
+    FEE_HISTORY.with(|fee_history| fee_history.borrow().get(&ledger_id).unwrap_or_default().0)
+}
+
+// Fees are not stored per transfer, so each entry is charged the fee the ledger
+// expected after a correction, else the last fee sample taken at or before the entry, else
+// the configured fee. Entries none of these cover are left out, as are legs the ledger
+// deduplicated, which paid no fee.
+#[query(guard = "check_read_access")]
+pub fn get_fees_by_category(ledger_id: Principal) -> Vec<(String, u64)> {
+    let samples = FEE_HISTORY.with(|fee_history| fee_history.borrow().get(&ledger_id).unwrap_or_default().0);
+    let configured_fee = ledger_config(ledger_id).fee;
+
+    let mut fees = BTreeMap::<String, u64>::new();
+    TRANSFER_HISTORY.with(|history| {
+        for (_, entry) in history.borrow().iter() {
+            if entry.transfer.ledger_id() != ledger_id {
+                continue;
+            }
+            let Some(category) = entry.transfer.category() else {
+                continue;
+            };
+            let corrected_fee = entry.actual_fee.clone().and_then(|fee| u64::try_from(fee.0).ok());
+            let sampled_fee = entry.timestamp.and_then(|timestamp| {
+                samples
+                    .iter()
+                    .rev()
+                    .find(|(sampled_at, _)| *sampled_at <= timestamp)
+                    .map(|(_, fee)| *fee)
+            });
+            let Some(fee) = corrected_fee.or(sampled_fee).or(configured_fee) else {
+                continue;
+            };
+
+            let paid = fee.saturating_mul(entry.paid_legs().len() as u64);
+            let total = fees.entry(category.to_string()).or_default();
+            *total = total.saturating_add(paid);
+        }
+    });
+    fees.into_iter().collect()
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_ledger_latency(ledger_id: Principal) -> LatencyStats {
+    ledger_latency(ledger_id)
+}
+
+// (recipient, ledger_id, claim) for every authorized claim.
+#[query(guard = "check_read_access")]
+pub fn get_claims() -> Vec<(Principal, Principal, Claim)> {
+    CLAIMS.with(|claims| {
+        claims
+            .borrow()
+            .iter()
+            .map(|((recipient, ledger_id), claim)| (recipient, ledger_id, claim))
+            .collect::<Vec<(Principal, Principal, Claim)>>()
+    })
+}
+
+// Not behind check_read_access so recipients can always see their own claim.
+#[query]
+pub fn get_my_claim(ledger_id: Principal) -> Option<Claim> {
+    CLAIMS.with(|claims| claims.borrow().get(&(ic_cdk::caller(), ledger_id)))
+}
+
+#[query]
+pub fn get_schema_version() -> u32 {
+    schema_version()
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_conditional_transfers() -> Vec<(ConditionalTransferId, ConditionalTransferRecord)> {
+    CONDITIONAL_TRANSFERS.with(|records| {
+        records.borrow().iter().collect::<Vec<(ConditionalTransferId, ConditionalTransferRecord)>>()
+    })
+}
+
+// Scans the whole history on every call, oldest first, and returns at most
+// MAX_QUERY_HISTORY_RESULTS matches; narrow the time range to page through more.
+#[query(guard = "check_read_access")]
+pub fn query_history(filter: HistoryFilter) -> Vec<(u64, TransferHistory)> {
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| filter.matches(entry))
+            .take(MAX_QUERY_HISTORY_RESULTS)
+            .map(|(id, entry)| (id, entry.transfer))
+            .collect::<Vec<(u64, TransferHistory)>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_ledger_stats(ledger_id: Principal) -> LedgerStats {
+    ledger_stats(ledger_id)
+}
+
+#[query]
+pub fn get_explorer_url(ledger_id: Principal, block_index: BlockIndex) -> Option<String> {
+    explorer_url(ledger_id, &block_index)
+}
+
+// Newest first; limit defaults to and is capped at MAX_RECENT_ERRORS.
+#[query(guard = "check_read_access")]
+pub fn get_recent_errors(limit: Option<u64>) -> Vec<ErrorRecord> {
+    let limit = limit.unwrap_or(MAX_RECENT_ERRORS).min(MAX_RECENT_ERRORS);
+    RECENT_ERRORS.with(|errors| {
+        errors
+            .borrow()
+            .iter()
+            .rev()
+            .take(limit as usize)
+            .map(|(_, record)| record)
+            .collect::<Vec<ErrorRecord>>()
+    })
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_lifetime_outflow(ledger_id: Principal) -> LifetimeOutflow {
+    let cap = ledger_config(ledger_id).lifetime_outflow_cap;
+    let transferred = lifetime_outflow(ledger_id);
+    LifetimeOutflow {
+        cap,
+        transferred,
+        remaining: cap.map(|cap| cap.saturating_sub(transferred)),
+    }
+}
+
+// Last heartbeat per signer in nanoseconds, including principals that are no longer
+// controllers.
+#[query(guard = "check_read_access")]
+pub fn get_signer_heartbeats() -> Vec<(Principal, u64)> {
+    SIGNER_HEARTBEATS.with(|heartbeats| heartbeats.borrow().iter().collect())
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_active_timers() -> Vec<TimerInfo> {
+    active_timers()
+}
+
+#[query(guard = "check_read_access")]
+pub fn verify_timers_rearmed() -> bool {
+    timers_match_persisted_jobs()
+}
+
+#[query(guard = "check_read_access")]
+pub fn get_transfer_status(tracking_id: TrackingId) -> Result<TransferStatus, String> {
+    SCHEDULED_TRANSFERS.with(|scheduled_transfers| scheduled_transfers.borrow().get(&tracking_id))
+        .map(|scheduled| TransferStatus::from(scheduled.status))
+        .ok_or(format!("Transfer {} not found", tracking_id))
+}
+
+// Sorted and deduplicated. Scans the full history on every call; only legs that were
+// actually paid are in history, so failed legs of a batch are not included.
+#[query(guard = "check_read_access")]
+pub fn get_all_recipients(ledger_id: Principal) -> Vec<Principal> {
+    TRANSFER_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.transfer.ledger_id() == ledger_id)
+            .flat_map(|(_, entry)| entry.transfer.legs())
+            .map(|leg| leg.receiving_principal)
+            .collect::<BTreeSet<Principal>>()
+            .into_iter()
+            .collect()
     })
-}
\ No newline at end of file
+}
@@ -1,27 +1,113 @@
-use std::borrow::Cow;
+use std::{ borrow::Cow, cell::{ Cell, RefCell }, collections::{ BTreeSet, HashMap }, time::Duration };
 
-use candid::{ CandidType, Decode, Encode, Principal };
+use candid::{ CandidType, Decode, Encode, Principal, Reserved };
 use ic_cdk::{
     api::{ management_canister::main::{ canister_status, CanisterIdRecord }, time },
+    query,
     update,
 };
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::{ storable::Bound, Storable };
-use icrc_ledger_types::icrc1::{ account::Account, transfer::{ BlockIndex, NumTokens, TransferArg, TransferError } };
+use icrc_ledger_types::icrc1::{
+    account::{ Account, Subaccount },
+    transfer::{ BlockIndex, NumTokens, TransferArg, TransferError },
+};
 use serde::{ Deserialize, Serialize };
 
-use crate::TRANSFER_HISTORY;
+use crate::{
+    LEDGER_CONFIGS,
+    RECURRING_TRANSFERS,
+    SAFE_RECIPIENT_CANISTERS,
+    PERMITTED_LEDGERS,
+    OBSERVERS,
+    SCHEDULED_TRANSFERS,
+    KNOWN_RECIPIENTS,
+    QUARANTINED_TRANSFERS,
+    BUDGETS,
+    BATCH_RUNS,
+    CLAIMS,
+    CONDITIONAL_TRANSFERS,
+    FEE_HISTORY,
+    RECENT_ERRORS,
+    LIFETIME_OUTFLOW,
+    SIGNER_HEARTBEATS,
+    HISTORY_ARCHIVES,
+    TRANSFER_HISTORY,
+    TREASURY_CONFIG,
+};
+
+const MIN_RECURRING_INTERVAL_NS: u64 = 60_000_000_000;
+const SWEEP_FEE_BUFFER_FEES: u64 = 3;
+const PRICE_SCALE: u64 = 100_000_000;
+const DEFAULT_PRICE_CACHE_TTL_NS: u64 = 300_000_000_000;
+const DEFAULT_TEMPORARILY_UNAVAILABLE_RETRIES: u32 = 2;
+const MAX_TEMPORARILY_UNAVAILABLE_RETRIES: u32 = 10;
+const LEDGER_SYMBOL_CACHE_TTL_NS: u64 = 3_600_000_000_000;
+const FEE_SAMPLE_INTERVAL: Duration = Duration::from_secs(3_600);
+const HISTORY_ARCHIVE_INTERVAL: Duration = Duration::from_secs(3_600);
+// How long a timer-driven job waits before trying again when its ledger is busy.
+const LEDGER_BUSY_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_ARCHIVE_BATCH: usize = 500;
+// Well below the 2MB inter-canister message limit, leaving room for the candid framing.
+const MAX_ARCHIVE_BATCH_BYTES: usize = 1_500_000;
+// A week of hourly samples per ledger.
+const MAX_FEE_SAMPLES: usize = 168;
+const MAX_ERROR_RECORDS: u64 = 500;
+const MAX_BALANCE_LEDGERS: usize = 20;
+const MAX_RECURRING_EVENTS: usize = 20;
+const CONFIG_SNAPSHOT_VERSION: u32 = 1;
+
+// Per-message instruction limit for update calls, of which batches may plan to use
+// BATCH_INSTRUCTION_BUDGET_PERCENT.
+const MESSAGE_INSTRUCTION_LIMIT: u64 = 40_000_000_000;
+const BATCH_INSTRUCTION_BUDGET_PERCENT: u64 = 50;
+const DEFAULT_LEG_INSTRUCTION_ESTIMATE: u64 = 100_000_000;
+
+thread_local! {
+    static RECURRING_TIMERS: RefCell<HashMap<RecurringId, TimerId>> = RefCell::new(HashMap::new());
+    static SCHEDULE_TIMERS: RefCell<HashMap<ScheduleId, TimerId>> = RefCell::new(HashMap::new());
+    static LEG_INSTRUCTION_ESTIMATE: Cell<u64> = const { Cell::new(DEFAULT_LEG_INSTRUCTION_ESTIMATE) };
+    static LEDGERS_IN_FLIGHT: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    // The fee sampling interval timer and the time it was started at.
+    static FEE_SAMPLING_TIMER: Cell<Option<(TimerId, u64)>> = const { Cell::new(None) };
+    // Same for history archiving, plus whether a round is currently awaiting the archive.
+    static HISTORY_ARCHIVING_TIMER: Cell<Option<(TimerId, u64)>> = const { Cell::new(None) };
+    static HISTORY_ARCHIVING_IN_FLIGHT: Cell<bool> = const { Cell::new(false) };
+    static LEDGER_SYMBOL_CACHE: RefCell<HashMap<Principal, (String, u64)>> = RefCell::new(
+        HashMap::new()
+    );
+    static LEDGER_LATENCY: RefCell<HashMap<Principal, (LatencyStats, u128)>> = RefCell::new(
+        HashMap::new()
+    );
+    static LEDGER_STATS: RefCell<HashMap<Principal, LedgerStats>> = RefCell::new(HashMap::new());
+    static PRICE_CACHE: RefCell<HashMap<(Principal, Principal), (u64, u64)>> = RefCell::new(
+        HashMap::new()
+    );
+}
+
+pub type RecurringId = u64;
+pub type ScheduleId = u64;
+pub type QuarantineId = u64;
+pub type BudgetId = u64;
+pub type BatchRunId = u64;
+pub type ConditionalTransferId = u64;
+// Async transfers run as scheduled transfers due immediately, so their tracking id is
+// the schedule id.
+pub type TrackingId = ScheduleId;
 
 #[derive(CandidType, Serialize, Clone, Deserialize)]
 pub struct TransferToPrincipal {
     pub receiving_principal: Principal,
     pub amount: u64,
     pub ledger_id: Principal,
+    pub category: Option<String>,
 }
 
 #[derive(CandidType, Serialize, Clone, Deserialize)]
 pub struct TransferToMultiple {
     pub principals: Vec<PrincipalTransfer>,
     pub ledger_id: Principal,
+    pub category: Option<String>,
 }
 
 #[derive(CandidType, Serialize, Clone, Deserialize)]
@@ -30,14 +116,43 @@ pub struct PrincipalTransfer {
     pub amount: u64,
 }
 
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct LinkedTransfer {
+    pub ledger_id: Principal,
+    pub recipient: Principal,
+    pub recipient_amount: u64,
+    pub split_account: Principal,
+    pub split_amount: u64,
+    pub split_completed: bool,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct SplitTransferReceipt {
+    pub recipient_block_index: BlockIndex,
+    pub split_result: Result<BlockIndex, String>,
+    pub recipient_explorer_url: Option<String>,
+    pub split_explorer_url: Option<String>,
+    pub balance_after: Option<NumTokens>,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct TransferReceipt {
+    pub block_index: BlockIndex,
+    pub explorer_url: Option<String>,
+    pub balance_after: Option<NumTokens>,
+}
+
 #[derive(CandidType, Serialize, Clone, Deserialize)]
 pub enum TransferHistory {
     TransferToPrincipal(TransferToPrincipal),
     TransferToMultiple(TransferToMultiple),
+    LinkedTransfer(LinkedTransfer),
+    TestTransfer(TransferToPrincipal),
+    EmergencyDrain(TransferToPrincipal),
 }
 
 impl Storable for TransferHistory {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -48,201 +163,4853 @@ impl Storable for TransferHistory {
     const BOUND: Bound = Bound::Unbounded;
 }
 
-#[update]
-pub async fn validate_transfer_to_multiple(arg: TransferToMultiple) -> Result<String, String> {
-    if arg.principals.is_empty() {
-        return Err("No principals provided for transfer".to_string());
+impl TransferHistory {
+    pub fn ledger_id(&self) -> Principal {
+        match self {
+            TransferHistory::TransferToPrincipal(arg) => arg.ledger_id,
+            TransferHistory::TransferToMultiple(arg) => arg.ledger_id,
+            TransferHistory::LinkedTransfer(linked) => linked.ledger_id,
+            TransferHistory::TestTransfer(arg) => arg.ledger_id,
+            TransferHistory::EmergencyDrain(arg) => arg.ledger_id,
+        }
     }
 
-    for principal_transfer in &arg.principals {
-        if principal_transfer.amount == 0 {
-            return Err(
-                format!(
-                    "Transfer amount for principal {} must be greater than 0",
-                    principal_transfer.receiving_principal
-                )
-            );
+    pub fn category(&self) -> Option<&str> {
+        match self {
+            TransferHistory::TransferToPrincipal(arg) => arg.category.as_deref(),
+            TransferHistory::TransferToMultiple(arg) => arg.category.as_deref(),
+            TransferHistory::LinkedTransfer(_) |
+            TransferHistory::TestTransfer(_) |
+            TransferHistory::EmergencyDrain(_) => None,
         }
     }
 
-    if arg.ledger_id == Principal::anonymous() {
-        return Err("Invalid ledger ID".to_string());
+    pub fn legs(&self) -> Vec<PrincipalTransfer> {
+        match self {
+            TransferHistory::TransferToPrincipal(arg) |
+            TransferHistory::TestTransfer(arg) |
+            TransferHistory::EmergencyDrain(arg) =>
+                vec![PrincipalTransfer {
+                    receiving_principal: arg.receiving_principal,
+                    amount: arg.amount,
+                }],
+            TransferHistory::TransferToMultiple(arg) => arg.principals.clone(),
+            TransferHistory::LinkedTransfer(linked) => {
+                let mut legs = vec![PrincipalTransfer {
+                    receiving_principal: linked.recipient,
+                    amount: linked.recipient_amount,
+                }];
+                if linked.split_completed {
+                    legs.push(PrincipalTransfer {
+                        receiving_principal: linked.split_account,
+                        amount: linked.split_amount,
+                    });
+                }
+                legs
+            }
+        }
     }
+}
 
-    let total_amount: u64 = arg.principals
-        .iter()
-        .map(|p| p.amount)
-        .sum();
-    let recipient_count = arg.principals.len();
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize, PartialEq, Debug, Default)]
+pub enum OnDuplicate {
+    #[default]
+    TreatAsSuccess,
+    ReturnError,
+}
 
-    Ok(
-        format!(
-            "Transfer {} tokens to {} recipients from ledger {}",
-            total_amount,
-            recipient_count,
-            arg.ledger_id
-        )
-    )
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct DuplicateResolution {
+    pub receiving_principal: Principal,
+    pub duplicate_of: BlockIndex,
+    pub handling: OnDuplicate,
 }
 
-#[update]
-pub async fn validate_transfer_to_principal(arg: TransferToPrincipal) -> Result<String, String> {
-    if arg.amount == 0 {
-        return Err("Transfer amount must be greater than 0".to_string());
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct HistoryEntry {
+    pub transfer: TransferHistory,
+    pub recurring_id: Option<RecurringId>,
+    // Legs the ledger deduplicated against an earlier block; no new funds moved for them.
+    pub duplicates: Option<Vec<DuplicateResolution>>,
+    pub timestamp: Option<u64>,
+    pub caller: Option<Principal>,
+    pub block_indices: Option<Vec<BlockIndex>>,
+    pub ledger_symbol: Option<String>,
+    // Set when a leg was rejected with BadFee and resent with the ledger's expected fee.
+    // None on entries recorded before fee correction existed.
+    pub fee_corrected: Option<bool>,
+    pub original_fee: Option<NumTokens>,
+    pub actual_fee: Option<NumTokens>,
+}
+
+impl HistoryEntry {
+    pub fn new(transfer: TransferHistory, caller: Principal) -> Self {
+        HistoryEntry {
+            transfer,
+            recurring_id: None,
+            duplicates: None,
+            timestamp: Some(time()),
+            caller: Some(caller),
+            block_indices: None,
+            ledger_symbol: None,
+            fee_corrected: Some(false),
+            original_fee: None,
+            actual_fee: None,
+        }
     }
 
-    if arg.receiving_principal == Principal::anonymous() {
-        return Err("Cannot transfer to anonymous principal".to_string());
+    fn legacy(transfer: TransferHistory) -> Self {
+        HistoryEntry {
+            transfer,
+            recurring_id: None,
+            duplicates: None,
+            timestamp: None,
+            caller: None,
+            block_indices: None,
+            ledger_symbol: None,
+            fee_corrected: None,
+            original_fee: None,
+            actual_fee: None,
+        }
     }
 
-    if arg.ledger_id == Principal::anonymous() {
-        return Err("Invalid ledger ID".to_string());
+    // The legs that moved funds. A leg the ledger answered with Duplicate is listed with
+    // the block it duplicates, so it is dropped by matching duplicate_of against the
+    // block index recorded for it.
+    pub fn paid_legs(&self) -> Vec<PrincipalTransfer> {
+        let legs = self.transfer.legs();
+        let (Some(duplicates), Some(block_indices)) = (&self.duplicates, &self.block_indices) else {
+            return legs;
+        };
+        legs.into_iter()
+            .zip(block_indices)
+            .filter(|(leg, block_index)| {
+                !duplicates.iter().any(|duplicate| {
+                    duplicate.receiving_principal == leg.receiving_principal &&
+                        &duplicate.duplicate_of == *block_index
+                })
+            })
+            .map(|(leg, _)| leg)
+            .collect()
     }
 
-    Ok(
-        format!(
-            "Transfer {} tokens to principal {} from ledger {}",
-            arg.amount,
-            arg.receiving_principal,
-            arg.ledger_id
-        )
-    )
+    fn record_fee_correction(&mut self, correction: Option<&FeeCorrection>) {
+        if let Some(correction) = correction {
+            self.fee_corrected = Some(true);
+            self.original_fee = Some(correction.original_fee.clone());
+            self.actual_fee = Some(correction.actual_fee.clone());
+        }
+    }
 }
 
-#[update]
-pub async fn transfer_to_multiple(arg: TransferToMultiple) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    if !is_controller(caller).await {
-        return Err("Caller is not a controller".to_string());
+impl Storable for HistoryEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    validate_transfer_to_multiple(arg.clone()).await?;
-
-    let balance = get_tokens_balance(arg.ledger_id).await?;
-    let total_amount: u64 = arg.principals
-        .iter()
-        .map(|p| p.amount)
-        .sum();
-    if balance < NumTokens::from(total_amount) {
-        return Err(
-            format!(
-                "Insufficient balance: {} tokens available, {} tokens requested",
-                balance,
-                total_amount
-            )
-        );
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        // Entries written before history entries were wrapped hold a bare TransferHistory.
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|_| {
+            HistoryEntry::legacy(Decode!(bytes.as_ref(), TransferHistory).unwrap())
+        })
     }
 
-    for principal in arg.principals.clone() {
-        let transfer_amount_arg = TransferArg {
-            to: Account {
-                owner: principal.receiving_principal,
-                subaccount: None,
-            },
-            fee: None,
-            memo: None,
-            from_subaccount: None,
-            created_at_time: Some(time()),
-            amount: NumTokens::from(principal.amount),
-        };
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-        transfer_tokens(transfer_amount_arg, arg.ledger_id).await?;
-    }
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct RecurringTransfer {
+    pub transfer: TransferToPrincipal,
+    pub interval_ns: u64,
+    pub next_execution_ns: u64,
+    pub active: bool,
+    pub consecutive_shortfalls: Option<u32>,
+    // Set when the job paused itself after too many shortfalls in a row or because too
+    // few signers were online.
+    pub needs_attention: Option<bool>,
+    pub events: Option<Vec<RecurringEvent>>,
+    // The controller that created the job; each run is attributed to them. None for jobs
+    // created before this was recorded.
+    pub caller: Option<Principal>,
+}
 
-    let id = TRANSFER_HISTORY.with(|history| {
-        let history = history.borrow();
-        history.len() as u64
-    });
-    let transfer_history = TransferHistory::TransferToMultiple(arg.clone());
-    TRANSFER_HISTORY.with(|history| {
-        history.borrow_mut().insert(id + 1, transfer_history);
-    });
-    Ok(())
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum RecurringEvent {
+    SkippedForShortfall {
+        at_ns: u64,
+        message: String,
+    },
+    AutoPaused {
+        at_ns: u64,
+        consecutive_shortfalls: u32,
+    },
+    PausedForQuorum {
+        at_ns: u64,
+        message: String,
+    },
 }
 
-#[update]
-pub async fn transfer_to_principal(arg: TransferToPrincipal) -> Result<BlockIndex, String> {
-    let caller = ic_cdk::caller();
-    if !is_controller(caller).await {
-        return Err("Caller is not a controller".to_string());
+impl Storable for RecurringTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    validate_transfer_to_principal(arg.clone()).await?;
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 
-    let balance = get_tokens_balance(arg.ledger_id).await?;
-    if balance < NumTokens::from(arg.amount) {
-        return Err(
-            format!(
-                "Insufficient balance: {} tokens available, {} tokens requested",
-                balance,
-                arg.amount
-            )
-        );
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum TransferRequest {
+    TransferToPrincipal(TransferToPrincipal),
+    TransferToMultiple(TransferToMultiple),
+}
+
+impl TransferRequest {
+    fn ledger_id(&self) -> Principal {
+        match self {
+            TransferRequest::TransferToPrincipal(arg) => arg.ledger_id,
+            TransferRequest::TransferToMultiple(arg) => arg.ledger_id,
+        }
     }
 
-    let transfer_amount_arg = TransferArg {
-        to: Account {
-            owner: arg.receiving_principal,
-            subaccount: None,
-        },
-        fee: None,
-        memo: None,
-        from_subaccount: None,
-        created_at_time: Some(time()),
-        amount: NumTokens::from(arg.amount),
-    };
+    fn legs(&self) -> Vec<(Principal, u64)> {
+        match self {
+            TransferRequest::TransferToPrincipal(arg) => vec![(arg.receiving_principal, arg.amount)],
+            TransferRequest::TransferToMultiple(arg) =>
+                arg.principals
+                    .iter()
+                    .map(|p| (p.receiving_principal, p.amount))
+                    .collect(),
+        }
+    }
+}
 
-    let block_index = transfer_tokens(transfer_amount_arg, arg.ledger_id).await?;
-    let history_arg = TransferHistory::TransferToPrincipal(arg.clone());
-    let id = TRANSFER_HISTORY.with(|history| {
-        let history = history.borrow();
-        history.len() as u64
-    });
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct LegResult {
+    pub receiving_principal: Principal,
+    pub amount: u64,
+    pub result: Result<BlockIndex, String>,
+}
 
-    TRANSFER_HISTORY.with(|history| {
-        history.borrow_mut().insert(id + 1, history_arg);
-    });
-    Ok(block_index)
+// A resumable batch that stopped early; legs holds the results of every leg before
+// next_index across all runs so far.
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct BatchRun {
+    pub arg: TransferToMultiple,
+    pub caller: Principal,
+    pub next_index: u64,
+    pub legs: Vec<LegResult>,
 }
 
-async fn transfer_tokens(arg: TransferArg, ledger_id: Principal) -> Result<BlockIndex, String> {
-    ic_cdk
-        ::call::<(TransferArg,), (Result<BlockIndex, TransferError>,)>(
-            ledger_id,
-            "icrc1_transfer",
-            (arg,)
-        ).await
-        .map_err(|e| format!("failed to call ledger: {:?}", e))?
-        .0.map_err(|e| format!("ledger transfer error {:?}", e))
+impl Storable for BatchRun {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-async fn get_tokens_balance(ledger_id: Principal) -> Result<NumTokens, String> {
-    let owner = ic_cdk::id();
-    let user_balance = ic_cdk
-        ::call::<(Account,), (NumTokens,)>(ledger_id, "icrc1_balance_of", (
-            Account {
-                owner,
-                subaccount: None,
-            },
-        )).await
-        .map_err(|e| format!("failed to call ledger: {:?}", e))?.0;
-    Ok(user_balance)
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum BatchProgress {
+    Completed(Vec<LegResult>),
+    ResumeNeeded {
+        batch_id: BatchRunId,
+        next_index: u64,
+    },
 }
 
-async fn is_controller(principal: Principal) -> bool {
-    let canister_id = ic_cdk::id();
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum ScheduleStatus {
+    Pending,
+    Executing,
+    Executed(Vec<LegResult>),
+    Failed(String),
+    Cancelled,
+    // Not executed because too few signers were online; see release_scheduled_transfer.
+    AwaitingReview(String),
+}
 
-    let result = canister_status(CanisterIdRecord { canister_id }).await;
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct ScheduledTransfer {
+    pub payload: TransferRequest,
+    pub execute_at_ns: u64,
+    pub status: ScheduleStatus,
+    // The controller that queued the transfer; history and budgets attribute the payment
+    // to them. None for transfers queued before this was recorded.
+    pub caller: Option<Principal>,
+    // Queued by transfer_to_principal_async or transfer_to_multiple_async. Those run on
+    // behalf of a signer who is online right now, so the signer quorum does not apply.
+    pub immediate: Option<bool>,
+}
 
-    match result {
-        Ok(status) => { status.0.settings.controllers.contains(&principal) }
-        Err(error) => {
-            let error_message = format!("{:?}", error);
-            if error_message.contains(&principal.to_string()) {
-                true
-            } else {
-                false
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum TransferStatus {
+    Pending,
+    Completed {
+        block_indices: Vec<BlockIndex>,
+    },
+    PartiallyCompleted {
+        legs: Vec<LegResult>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl From<ScheduleStatus> for TransferStatus {
+    fn from(status: ScheduleStatus) -> Self {
+        match status {
+            ScheduleStatus::Pending | ScheduleStatus::Executing | ScheduleStatus::AwaitingReview(_) =>
+                TransferStatus::Pending,
+            ScheduleStatus::Executed(legs) => {
+                if legs.iter().all(|leg| leg.result.is_ok()) {
+                    TransferStatus::Completed {
+                        block_indices: legs
+                            .into_iter()
+                            .filter_map(|leg| leg.result.ok())
+                            .collect(),
+                    }
+                } else if legs.iter().any(|leg| leg.result.is_ok()) {
+                    TransferStatus::PartiallyCompleted { legs }
+                } else {
+                    TransferStatus::Failed {
+                        reason: legs
+                            .into_iter()
+                            .filter_map(|leg| leg.result.err())
+                            .collect::<Vec<String>>()
+                            .join("; "),
+                    }
+                }
             }
+            ScheduleStatus::Failed(reason) => TransferStatus::Failed { reason },
+            ScheduleStatus::Cancelled => TransferStatus::Failed { reason: "Cancelled".to_string() },
         }
     }
-}
\ No newline at end of file
+}
+
+impl Storable for ScheduledTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize, PartialEq, Debug)]
+pub enum QuarantineRule {
+    AmountAbove(u64),
+    NewRecipientAmountAbove(u64),
+    // Recorded on transfers held by TreasuryConfig::new_recipient_delay_ns; not a
+    // selectable quarantine_rule.
+    NewRecipientDelay(u64),
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum QuarantineStatus {
+    Pending,
+    Released(Result<Vec<BlockIndex>, String>),
+    Rejected,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct QuarantinedTransfer {
+    pub request: TransferRequest,
+    pub rule: QuarantineRule,
+    pub caller: Principal,
+    pub quarantined_at_ns: u64,
+    pub status: QuarantineStatus,
+    pub release_after_ns: Option<u64>,
+}
+
+impl Storable for QuarantinedTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Periods are fixed windows of period_ns aligned to the Unix epoch, so a daily budget
+// resets at midnight UTC rather than a day after the first transfer.
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct Budget {
+    pub category: String,
+    pub ledger_id: Principal,
+    pub period_ns: u64,
+    pub amount: u64,
+    pub block_when_exhausted: bool,
+}
+
+impl Storable for Budget {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub enum ConditionalOutcome {
+    Executed(BlockIndex),
+    ConditionNotMet {
+        balance: NumTokens,
+        min_balance_required: u64,
+    },
+    Failed(String),
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct ConditionalTransferRecord {
+    pub arg: TransferToPrincipal,
+    pub min_balance_required: u64,
+    pub caller: Principal,
+    pub evaluated_at_ns: u64,
+    pub outcome: ConditionalOutcome,
+}
+
+impl Storable for ConditionalTransferRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Running totals for one recipient on one ledger; claimable is authorized - claimed.
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct Claim {
+    pub authorized: u64,
+    pub claimed: u64,
+}
+
+impl Storable for Claim {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Response times of calls to one ledger since the last upgrade, in nanoseconds.
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct LatencyStats {
+    pub calls: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    pub avg_ns: u64,
+}
+
+// Transfer outcomes per ledger since the last upgrade. call_failures are calls that
+// never got an answer from the ledger (rejected, trapped, unreachable); ledger_rejections
+// are transfers the ledger answered with a TransferError.
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct LedgerStats {
+    pub transfers_succeeded: u64,
+    pub call_failures: u64,
+    pub ledger_rejections: u64,
+    pub fee_corrections: u64,
+}
+
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize, PartialEq, Debug)]
+pub enum ErrorKind {
+    CallFailure,
+    LedgerRejection,
+}
+
+// One failed transfer attempt, kept in a bounded log of the latest MAX_ERROR_RECORDS.
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct ErrorRecord {
+    pub timestamp_ns: u64,
+    pub ledger_id: Principal,
+    pub recipient: Principal,
+    pub amount: NumTokens,
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Storable for ErrorRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize, PartialEq, Debug)]
+pub enum TimerKind {
+    RecurringTransfer,
+    ScheduledTransfer,
+    FeeSampling,
+    HistoryArchiving,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct TimerInfo {
+    pub kind: TimerKind,
+    // The recurring or schedule id the timer fires for.
+    pub id: Option<u64>,
+    pub next_fire_ns: Option<u64>,
+    // Set when the armed timers and the persisted records disagree.
+    pub mismatch: Option<String>,
+}
+
+// (sampled_at_ns, fee) pairs, oldest first.
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct FeeSamples(pub Vec<(u64, u64)>);
+
+impl Storable for FeeSamples {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Everything an operator configures, as exported by export_config_snapshot. One-shot
+// scheduled transfers and history are deliberately left out: they are activity, not
+// configuration, and replaying them on another canister could pay recipients twice.
+// For the same reason imported recurring transfers arrive paused and only run once
+// resumed with resume_recurring_transfer.
+#[derive(Serialize, Deserialize)]
+struct ConfigSnapshot {
+    version: u32,
+    treasury_config: TreasuryConfig,
+    ledger_configs: Vec<(Principal, LedgerConfig)>,
+    safe_recipient_canisters: Vec<Principal>,
+    permitted_ledgers: Vec<Principal>,
+    observers: Vec<Principal>,
+    budgets: Vec<(BudgetId, Budget)>,
+    recurring_transfers: Vec<(RecurringId, RecurringTransfer)>,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct SweepArg {
+    pub ledger_id: Principal,
+    pub destination: Principal,
+    pub keep_amount: u64,
+    pub maintain_fee_buffer: bool,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct TokenPrice {
+    pub ledger_id: Principal,
+    // Reference-ledger base units per PRICE_SCALE base units of ledger_id.
+    pub price: u64,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct TokenValuation {
+    pub ledger_id: Principal,
+    pub balance: NumTokens,
+    pub value: u64,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct PortfolioValue {
+    pub reference_ledger: Principal,
+    pub total_value: u64,
+    pub valued: Vec<TokenValuation>,
+    pub unvalued: Vec<(Principal, String)>,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct TransferSimulation {
+    pub total_amount: u64,
+    pub fee: NumTokens,
+    pub total_fees: NumTokens,
+    pub total_cost: NumTokens,
+    pub balance: NumTokens,
+    pub balance_after: NumTokens,
+    pub warnings: Vec<String>,
+}
+
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize)]
+pub enum BatchSizeLimit {
+    Fixed(u64),
+    Estimated,
+}
+
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize, Default)]
+pub enum BatchOrder {
+    #[default]
+    InputOrder,
+    LargestFirst,
+    SmallestFirst,
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct TreasuryConfig {
+    pub verify_recipient_canisters: bool,
+    pub quarantine_rule: Option<QuarantineRule>,
+    pub on_duplicate: Option<OnDuplicate>,
+    pub batch_size_limit: Option<BatchSizeLimit>,
+    pub price_oracle: Option<Principal>,
+    pub price_cache_ttl_ns: Option<u64>,
+    pub temporarily_unavailable_retries: Option<u32>,
+    pub record_ledger_symbol: Option<bool>,
+    pub restrict_to_permitted_ledgers: Option<bool>,
+    pub main_subaccount: Option<Subaccount>,
+    pub private_history: Option<bool>,
+    pub paused: Option<bool>,
+    pub new_recipient_delay_ns: Option<u64>,
+    // Consecutive insufficient-balance failures after which a recurring transfer
+    // pauses itself; None keeps retrying every cycle.
+    pub recurring_shortfall_limit: Option<u32>,
+    pub batch_order: Option<BatchOrder>,
+    pub auto_correct_fee: Option<bool>,
+    pub signer_quorum: Option<SignerQuorum>,
+    pub history_archive: Option<ArchiveConfig>,
+}
+
+// History entries older than older_than_ns are moved to canister_id, which must accept
+// append_history(vec { record { nat64; HistoryEntry } }) and answer
+// get_history_entry(nat64) -> (opt HistoryEntry) as a query.
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize)]
+pub struct ArchiveConfig {
+    pub canister_id: Principal,
+    pub older_than_ns: u64,
+}
+
+// History ids first_id..=last_id, moved to canister_id at archived_at_ns. The ids are
+// never handed out again.
+#[derive(CandidType, Serialize, Clone, Deserialize)]
+pub struct ArchivedRange {
+    pub last_id: u64,
+    pub canister_id: Principal,
+    pub archived_at_ns: u64,
+}
+
+impl Storable for ArchivedRange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Scheduled and recurring transfers only execute on their own while at least
+// min_signers controllers have called heartbeat within the last window_ns.
+#[derive(CandidType, Serialize, Clone, Copy, Deserialize)]
+pub struct SignerQuorum {
+    pub min_signers: u32,
+    pub window_ns: u64,
+}
+
+impl Storable for TreasuryConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Clone, Deserialize, Default)]
+pub struct LedgerConfig {
+    pub reserve_amount: u64,
+    pub balance_method: Option<String>,
+    pub transfer_method: Option<String>,
+    // Sent as the explicit fee of every transfer on this ledger; None lets the ledger
+    // charge its current fee.
+    pub fee: Option<u64>,
+    // Block explorer link with {ledger} and {index} placeholders, e.g.
+    // "https://dashboard.internetcomputer.org/tokens/{ledger}/transaction/{index}".
+    pub explorer_url_template: Option<String>,
+    pub lifetime_outflow_cap: Option<u64>,
+}
+
+impl Storable for LedgerConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Shape-only checks; see validate_transfer_to_multiple_with_balance for a check
+// against the live balance.
+#[query]
+pub fn validate_transfer_to_multiple(arg: TransferToMultiple) -> Result<String, String> {
+    if arg.principals.is_empty() {
+        return Err("No principals provided for transfer".to_string());
+    }
+
+    for (index, principal_transfer) in arg.principals.iter().enumerate() {
+        if principal_transfer.amount == 0 {
+            return Err(
+                format!(
+                    "Transfer amount for principal {} must be greater than 0",
+                    principal_transfer.receiving_principal
+                )
+            );
+        }
+
+        validate_recipient(
+            &format!("principals[{}].receiving_principal", index),
+            principal_transfer.receiving_principal
+        )?;
+    }
+
+    validate_ledger_id(arg.ledger_id)?;
+
+    let total_amount = batch_total(&arg.principals)?;
+    let recipient_count = arg.principals.len();
+    // Queries cannot ask the ledger for its fee, so the fee-inclusive total is only shown
+    // when a fee is configured for the ledger.
+    let fees = match ledger_config(arg.ledger_id).fee {
+        Some(fee) =>
+            format!(
+                " ({} including fees)",
+                batch_total_cost(total_amount, fee, recipient_count as u64)?
+            ),
+        None => String::new(),
+    };
+
+    Ok(
+        format!(
+            "Transfer {} tokens{} to {} recipients from ledger {}",
+            total_amount,
+            fees,
+            recipient_count,
+            arg.ledger_id
+        )
+    )
+}
+
+fn batch_total(principals: &[PrincipalTransfer]) -> Result<u64, String> {
+    principals
+        .iter()
+        .try_fold(0u64, |total, p| total.checked_add(p.amount))
+        .ok_or("Total transfer amount overflows".to_string())
+}
+
+fn batch_total_cost(total_amount: u64, fee: u64, transfer_count: u64) -> Result<u64, String> {
+    fee
+        .checked_mul(transfer_count)
+        .and_then(|fees| fees.checked_add(total_amount))
+        .ok_or("Total transfer cost including fees overflows".to_string())
+}
+
+fn fee_as_u64(fee: &NumTokens) -> Result<u64, String> {
+    u64::try_from(fee.0.clone()).map_err(|_| "Ledger fee exceeds u64".to_string())
+}
+
+#[query]
+pub fn validate_transfer_to_principal(arg: TransferToPrincipal) -> Result<String, String> {
+    if arg.amount == 0 {
+        return Err("Transfer amount must be greater than 0".to_string());
+    }
+
+    validate_recipient("receiving_principal", arg.receiving_principal)?;
+    validate_ledger_id(arg.ledger_id)?;
+
+    Ok(
+        format!(
+            "Transfer {} tokens to principal {} from ledger {}",
+            arg.amount,
+            arg.receiving_principal,
+            arg.ledger_id
+        )
+    )
+}
+
+#[update(guard = "check_read_access")]
+pub async fn validate_transfer_to_multiple_with_balance(
+    arg: TransferToMultiple
+) -> Result<String, String> {
+    let summary = validate_transfer_to_multiple(arg.clone())?;
+    verify_recipients(&batch_recipients(&arg.principals)).await?;
+    let funding = check_batch(&arg).await?;
+    Ok(
+        format!(
+            "{}; operational balance {} covers the {} tokens required including fees",
+            summary,
+            funding.balance,
+            funding.total_cost
+        )
+    )
+}
+
+// Runs every per-leg policy against each recipient without transferring anything.
+// Budgets and the lifetime cap are checked against the running total of the legs up to
+// and including each one, so the first leg to cross a limit and every leg after it are
+// flagged. Problems with the whole batch, such as the treasury being paused or the
+// ledger being rejected, are reported on every leg, or as the error when the batch has
+// no legs to report them on.
+#[update]
+pub async fn validate_batch_policies(
+    arg: TransferToMultiple
+) -> Result<Vec<(usize, Vec<String>)>, String> {
+    check_controller_or_observer()?;
+
+    let batch_errors = [check_not_paused().err(), validate_ledger_id(arg.ledger_id).err()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<String>>();
+    if arg.principals.is_empty() {
+        let mut errors = batch_errors;
+        errors.push("No principals provided for transfer".to_string());
+        return Err(errors.join("; "));
+    }
+    let new_recipient_delay_ns = treasury_config().new_recipient_delay_ns;
+
+    let mut report = Vec::new();
+    let mut running_total = 0u64;
+    for (index, principal) in arg.principals.iter().enumerate() {
+        let mut violations = Vec::new();
+        violations.extend(batch_errors.iter().cloned());
+        if principal.amount == 0 {
+            violations.push("Transfer amount must be greater than 0".to_string());
+        }
+        if let Err(error) = validate_recipient("receiving_principal", principal.receiving_principal) {
+            violations.push(error);
+        }
+        if let Err(error) = verify_recipients(&[principal.receiving_principal]).await {
+            violations.push(error);
+        }
+        if let Some(rule) = matching_quarantine_rule(&[(principal.receiving_principal, principal.amount)]) {
+            violations.push(format!("Would be quarantined under rule {:?}", rule));
+        }
+        if let Some(delay_ns) = new_recipient_delay_ns {
+            if !is_known_recipient(principal.receiving_principal) {
+                violations.push(format!("New recipient; would be held for {} ns", delay_ns));
+            }
+        }
+
+        running_total = running_total.saturating_add(principal.amount);
+        if let Err(error) = check_budgets(arg.category.as_deref(), arg.ledger_id, running_total) {
+            violations.push(error);
+        }
+        if let Err(error) = check_lifetime_cap(arg.ledger_id, running_total) {
+            violations.push(error);
+        }
+        report.push((index, violations));
+    }
+    Ok(report)
+}
+
+#[update(guard = "check_read_access")]
+pub async fn validate_transfer_to_principal_with_balance(
+    arg: TransferToPrincipal
+) -> Result<String, String> {
+    let summary = validate_transfer_to_principal(arg.clone())?;
+    verify_recipients(&[arg.receiving_principal]).await?;
+    let balance = check_transfer_to_principal(&arg).await?;
+    Ok(format!("{}; operational balance {} covers the transfer", summary, balance))
+}
+
+const RESERVED_PRINCIPAL_CLASS: u8 = 0x7f;
+
+fn validate_recipient(field: &str, principal: Principal) -> Result<(), String> {
+    if principal == Principal::anonymous() {
+        return Err(format!("{}: cannot transfer to the anonymous principal", field));
+    }
+
+    if principal == Principal::management_canister() {
+        return Err(format!("{}: cannot transfer to the management canister", field));
+    }
+
+    if principal.as_slice().last() == Some(&RESERVED_PRINCIPAL_CLASS) {
+        return Err(format!("{}: principal {} uses the reserved principal class", field, principal));
+    }
+
+    Ok(())
+}
+
+fn validate_ledger_id(ledger_id: Principal) -> Result<(), String> {
+    if ledger_id == Principal::anonymous() || ledger_id == Principal::management_canister() {
+        return Err("ledger_id: invalid ledger ID".to_string());
+    }
+    check_ledger_permitted(ledger_id)
+}
+
+// Enforced both when validating input and right before every ledger call, so nothing
+// reaches a ledger that is missing from the whitelist while the restriction is on.
+fn check_ledger_permitted(ledger_id: Principal) -> Result<(), String> {
+    if !treasury_config().restrict_to_permitted_ledgers.unwrap_or_default() {
+        return Ok(());
+    }
+    if !PERMITTED_LEDGERS.with(|ledgers| ledgers.borrow().contains_key(&ledger_id)) {
+        return Err(format!("Ledger {} is not on the permitted ledger list", ledger_id));
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn transfer_to_multiple(arg: TransferToMultiple) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_multiple(arg.clone())?;
+    check_quarantine(&TransferRequest::TransferToMultiple(arg.clone()), caller)?;
+
+    execute_transfer_to_multiple(arg, caller).await.map(|_| ())
+}
+
+async fn execute_transfer_to_multiple(
+    arg: TransferToMultiple,
+    caller: Principal
+) -> Result<Vec<BlockIndex>, String> {
+    // Legs that fail do not stop the batch, and the paid ones are recorded either way;
+    // the caller gets an error naming the failed legs.
+    let legs = execute_batch(arg, caller).await?;
+    let failed = legs
+        .iter()
+        .filter_map(|leg| {
+            leg.result
+                .as_ref()
+                .err()
+                .map(|error| format!("{}: {}", leg.receiving_principal, error))
+        })
+        .collect::<Vec<String>>();
+    if !failed.is_empty() {
+        return Err(
+            format!(
+                "Partial completion: {} of {} legs failed: {}",
+                failed.len(),
+                legs.len(),
+                failed.join("; ")
+            )
+        );
+    }
+    Ok(
+        legs
+            .into_iter()
+            .filter_map(|leg| leg.result.ok())
+            .collect()
+    )
+}
+
+struct BatchFunding {
+    balance: NumTokens,
+    total_cost: NumTokens,
+}
+
+async fn check_batch(arg: &TransferToMultiple) -> Result<BatchFunding, String> {
+    if let Some(max_batch_size) = max_batch_size() {
+        if (arg.principals.len() as u64) > max_batch_size {
+            return Err(
+                format!(
+                    "Batch of {} recipients exceeds the maximum batch size of {}",
+                    arg.principals.len(),
+                    max_batch_size
+                )
+            );
+        }
+    }
+    check_batch_funding(arg).await
+}
+
+// Everything check_batch does except the batch size limit, which resumable batches
+// replace by stopping before they run out of instructions.
+async fn check_batch_funding(arg: &TransferToMultiple) -> Result<BatchFunding, String> {
+    validate_transfer_to_multiple(arg.clone())?;
+    let fee = check_configured_fee(arg.ledger_id).await?;
+
+    let balance = get_operational_tokens_balance(arg.ledger_id).await?;
+    let total_amount = batch_total(&arg.principals)?;
+    let funding = batch_funding(balance, total_amount, fee_as_u64(&fee)?, arg.principals.len() as u64)?;
+    check_budgets(arg.category.as_deref(), arg.ledger_id, total_amount)?;
+    check_lifetime_cap(arg.ledger_id, total_amount)?;
+    Ok(funding)
+}
+
+// Every leg pays its own fee, so the amounts alone can fit while the batch still
+// overdraws partway through, or overflows a u64.
+fn batch_funding(
+    balance: NumTokens,
+    total_amount: u64,
+    fee: u64,
+    transfer_count: u64
+) -> Result<BatchFunding, String> {
+    let total_cost = NumTokens::from(batch_total_cost(total_amount, fee, transfer_count)?);
+    if balance < total_cost {
+        return Err(
+            format!(
+                "Insufficient operational balance: {} tokens available, {} tokens required including fees ({} requested)",
+                balance,
+                total_cost,
+                total_amount
+            )
+        );
+    }
+    Ok(BatchFunding { balance, total_cost })
+}
+
+#[update]
+pub async fn transfer_to_principal(arg: TransferToPrincipal) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_principal(arg.clone())?;
+    check_quarantine(&TransferRequest::TransferToPrincipal(arg.clone()), caller)?;
+
+    execute_transfer_to_principal(arg, caller, None).await
+}
+
+// Same as transfer_to_principal, returning a receipt for display. include_balance_after
+// costs one more ledger call after the transfer to read the full balance.
+#[update]
+pub async fn transfer_to_principal_with_receipt(
+    arg: TransferToPrincipal,
+    include_balance_after: bool
+) -> Result<TransferReceipt, String> {
+    let ledger_id = arg.ledger_id;
+    let block_index = transfer_to_principal(arg).await?;
+    let balance_after = if include_balance_after {
+        balance_after_transfer(ledger_id).await
+    } else {
+        None
+    };
+    Ok(TransferReceipt {
+        explorer_url: explorer_url(ledger_id, &block_index),
+        block_index,
+        balance_after,
+    })
+}
+
+// The transfer already happened, so a failed balance read only leaves the field empty.
+async fn balance_after_transfer(ledger_id: Principal) -> Option<NumTokens> {
+    get_tokens_balance(ledger_id).await.ok()
+}
+
+// Executes arg only if the full live balance of the ledger, reserve included, is at
+// least min_balance_required. Executed, skipped and failed evaluations are all logged
+// to CONDITIONAL_TRANSFERS; executed ones additionally land in the transfer history.
+#[update]
+pub async fn conditional_transfer(
+    arg: TransferToPrincipal,
+    min_balance_required: u64
+) -> Result<ConditionalOutcome, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_principal(arg.clone())?;
+    let balance = get_tokens_balance(arg.ledger_id).await?;
+    let outcome = if balance < min_balance_required {
+        ConditionalOutcome::ConditionNotMet { balance, min_balance_required }
+    } else {
+        // A quarantined transfer is recorded as a failed evaluation so the audit trail
+        // shows the condition was met.
+        match check_quarantine(&TransferRequest::TransferToPrincipal(arg.clone()), caller) {
+            Err(error) => ConditionalOutcome::Failed(error),
+            Ok(()) =>
+                match execute_transfer_to_principal(arg.clone(), caller, None).await {
+                    Ok(block_index) => ConditionalOutcome::Executed(block_index),
+                    Err(error) => ConditionalOutcome::Failed(error),
+                },
+        }
+    };
+
+    let record = ConditionalTransferRecord {
+        arg,
+        min_balance_required,
+        caller,
+        evaluated_at_ns: time(),
+        outcome: outcome.clone(),
+    };
+    CONDITIONAL_TRANSFERS.with(|records| {
+        let mut records = records.borrow_mut();
+        let id = records
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+        records.insert(id, record);
+    });
+
+    match outcome {
+        ConditionalOutcome::Failed(error) => Err(error),
+        outcome => Ok(outcome),
+    }
+}
+
+async fn execute_transfer_to_principal(
+    arg: TransferToPrincipal,
+    caller: Principal,
+    recurring_id: Option<RecurringId>
+) -> Result<BlockIndex, String> {
+    verify_recipients(&[arg.receiving_principal]).await?;
+    let _guard = LedgerGuard::acquire(arg.ledger_id)?;
+    check_transfer_to_principal(&arg).await?;
+    let ledger_symbol = ledger_symbol_snapshot(arg.ledger_id).await;
+
+    let transfer_amount_arg = transfer_arg(arg.receiving_principal, arg.amount);
+    let transfer = transfer_tokens(transfer_amount_arg, arg.ledger_id).await?;
+
+    let mut entry = HistoryEntry::new(TransferHistory::TransferToPrincipal(arg.clone()), caller);
+    entry.block_indices = Some(vec![transfer.block_index.clone()]);
+    entry.recurring_id = recurring_id;
+    entry.duplicates = transfer.duplicate.clone().map(|duplicate| vec![duplicate]);
+    entry.ledger_symbol = ledger_symbol;
+    entry.record_fee_correction(transfer.fee_correction.as_ref());
+    record_history(entry);
+
+    match transfer.duplicate {
+        Some(duplicate) if duplicate.handling == OnDuplicate::ReturnError =>
+            Err(already_executed_error(&duplicate)),
+        _ => Ok(transfer.block_index),
+    }
+}
+
+async fn check_transfer_to_principal(arg: &TransferToPrincipal) -> Result<NumTokens, String> {
+    validate_transfer_to_principal(arg.clone())?;
+
+    let balance = get_operational_tokens_balance(arg.ledger_id).await?;
+    // The fee comes out of the same account, so it must fit in the operational balance too
+    // or it would eat into the reserve.
+    let total_cost = transfer_fee(arg.ledger_id).await? + arg.amount;
+    if balance < total_cost {
+        return Err(
+            format!(
+                "Insufficient operational balance: {} tokens available, {} tokens required including fees ({} requested)",
+                balance,
+                total_cost,
+                arg.amount
+            )
+        );
+    }
+    check_budgets(arg.category.as_deref(), arg.ledger_id, arg.amount)?;
+    check_lifetime_cap(arg.ledger_id, arg.amount)?;
+    Ok(balance)
+}
+
+fn already_executed_error(duplicate: &DuplicateResolution) -> String {
+    format!(
+        "AlreadyExecuted: transfer to {} was already executed in block {}",
+        duplicate.receiving_principal,
+        duplicate.duplicate_of
+    )
+}
+
+#[update]
+pub async fn transfer_with_split(
+    recipient: Principal,
+    recipient_amount: u64,
+    split_account: Principal,
+    split_amount: u64,
+    ledger_id: Principal,
+    include_balance_after: Option<bool>
+) -> Result<SplitTransferReceipt, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    if recipient_amount == 0 || split_amount == 0 {
+        return Err("Transfer amounts must be greater than 0".to_string());
+    }
+    validate_recipient("recipient", recipient)?;
+    validate_recipient("split_account", split_account)?;
+    validate_ledger_id(ledger_id)?;
+    verify_recipients(&[recipient, split_account]).await?;
+    // A quarantined split is released as a plain two-leg batch.
+    let request = TransferRequest::TransferToMultiple(TransferToMultiple {
+        principals: vec![
+            PrincipalTransfer { receiving_principal: recipient, amount: recipient_amount },
+            PrincipalTransfer { receiving_principal: split_account, amount: split_amount }
+        ],
+        ledger_id,
+        category: None,
+    });
+    check_quarantine(&request, caller)?;
+
+    let _guard = LedgerGuard::acquire(ledger_id)?;
+    let balance = get_operational_tokens_balance(ledger_id).await?;
+    let total_amount = recipient_amount
+        .checked_add(split_amount)
+        .ok_or("Combined transfer amount overflows".to_string())?;
+    let total_cost = transfer_fee(ledger_id).await? * 2u64 + total_amount;
+    if balance < total_cost {
+        return Err(
+            format!(
+                "Insufficient operational balance: {} tokens available, {} tokens required including fees ({} requested)",
+                balance,
+                total_cost,
+                total_amount
+            )
+        );
+    }
+    check_lifetime_cap(ledger_id, total_amount)?;
+    let ledger_symbol = ledger_symbol_snapshot(ledger_id).await;
+
+    let recipient_transfer = transfer_tokens(transfer_arg(recipient, recipient_amount), ledger_id).await?;
+    let recipient_block_index = recipient_transfer.block_index;
+    let mut fee_correction = recipient_transfer.fee_correction;
+    let mut duplicates = Vec::from_iter(recipient_transfer.duplicate);
+    // As with transfer_to_principal, a recipient leg the ledger already executed is an
+    // error under ReturnError; the split leg is then not sent either.
+    let recipient_error = duplicates
+        .first()
+        .filter(|duplicate| duplicate.handling == OnDuplicate::ReturnError)
+        .map(already_executed_error);
+    let split_result = match &recipient_error {
+        Some(_) => Err("Not sent: the recipient transfer was already executed".to_string()),
+        None =>
+            transfer_tokens(transfer_arg(split_account, split_amount), ledger_id).await.and_then(
+                |transfer| {
+                    fee_correction = fee_correction.take().or(transfer.fee_correction);
+                    match transfer.duplicate {
+                        Some(duplicate) => {
+                            duplicates.push(duplicate.clone());
+                            match duplicate.handling {
+                                OnDuplicate::TreatAsSuccess => Ok(transfer.block_index),
+                                OnDuplicate::ReturnError => Err(already_executed_error(&duplicate)),
+                            }
+                        }
+                        None => Ok(transfer.block_index),
+                    }
+                }
+            ),
+    };
+
+    let mut block_indices = vec![recipient_block_index.clone()];
+    block_indices.extend(split_result.clone().ok());
+    let mut entry = HistoryEntry::new(
+        TransferHistory::LinkedTransfer(LinkedTransfer {
+            ledger_id,
+            recipient,
+            recipient_amount,
+            split_account,
+            split_amount,
+            split_completed: split_result.is_ok(),
+        }),
+        caller
+    );
+    entry.block_indices = Some(block_indices);
+    entry.duplicates = (!duplicates.is_empty()).then_some(duplicates);
+    entry.ledger_symbol = ledger_symbol;
+    entry.record_fee_correction(fee_correction.as_ref());
+    record_history(entry);
+    if let Some(error) = recipient_error {
+        return Err(error);
+    }
+
+    // A failed split leg is reported in the receipt rather than as an error, since the
+    // recipient has already been paid and retrying the whole call would pay them twice.
+    let balance_after = if include_balance_after.unwrap_or_default() {
+        balance_after_transfer(ledger_id).await
+    } else {
+        None
+    };
+    Ok(SplitTransferReceipt {
+        balance_after,
+        recipient_explorer_url: explorer_url(ledger_id, &recipient_block_index),
+        split_explorer_url: split_result
+            .as_ref()
+            .ok()
+            .and_then(|block_index| explorer_url(ledger_id, block_index)),
+        recipient_block_index,
+        split_result: split_result.map_err(|error| {
+            format!("Partial completion: recipient paid, split transfer failed: {}", error)
+        }),
+    })
+}
+
+fn batch_recipients(principals: &[PrincipalTransfer]) -> Vec<Principal> {
+    principals
+        .iter()
+        .map(|p| p.receiving_principal)
+        .collect()
+}
+
+// Held for the whole balance-check-then-transfer sequence so that a second call
+// interleaving at an await cannot pass the balance check against funds the first
+// call is about to spend. ic-cdk drops the future on a trap in a callback, so the
+// guard is released on every exit path. Recipient canisters are probed before it is
+// acquired, so a slow one cannot hold the ledger for everyone else.
+// Acquiring fails fast, which suits interactive callers who can retry. Timer-driven
+// jobs (scheduled, async and recurring transfers) fire as separate messages and can
+// come due together, so they check is_ledger_busy and re-arm themselves after
+// LEDGER_BUSY_RETRY_DELAY instead of recording a failure.
+struct LedgerGuard {
+    ledger_id: Principal,
+}
+
+impl LedgerGuard {
+    fn acquire(ledger_id: Principal) -> Result<Self, String> {
+        LEDGERS_IN_FLIGHT.with(|in_flight| {
+            if !in_flight.borrow_mut().insert(ledger_id) {
+                return Err(ledger_busy_error(ledger_id));
+            }
+            Ok(LedgerGuard { ledger_id })
+        })
+    }
+}
+
+fn ledger_busy_error(ledger_id: Principal) -> String {
+    format!("Another transfer on ledger {} is in progress, retry later", ledger_id)
+}
+
+// The guard is taken before anything is sent, so this error means the attempt did
+// nothing and can simply be repeated.
+fn is_ledger_busy<T>(result: &Result<T, String>, ledger_id: Principal) -> bool {
+    matches!(result, Err(error) if *error == ledger_busy_error(ledger_id))
+}
+
+impl Drop for LedgerGuard {
+    fn drop(&mut self) {
+        LEDGERS_IN_FLIGHT.with(|in_flight| {
+            in_flight.borrow_mut().remove(&self.ledger_id);
+        });
+    }
+}
+
+// Smoke test for a ledger: sends one base unit more than the ledger fee to
+// test_recipient through the regular transfer checks and records it as a
+// TestTransfer.
+#[update]
+pub async fn test_ledger(ledger_id: Principal, test_recipient: Principal) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    let fee = get_ledger_fee(ledger_id).await?;
+    let amount = u64::try_from((fee + 1u64).0).map_err(|_| "Ledger fee exceeds u64".to_string())?;
+    let arg = TransferToPrincipal {
+        receiving_principal: test_recipient,
+        amount,
+        ledger_id,
+        category: None,
+    };
+
+    check_quarantine(&TransferRequest::TransferToPrincipal(arg.clone()), caller)?;
+    verify_recipients(&[test_recipient]).await?;
+    let _guard = LedgerGuard::acquire(ledger_id)?;
+    check_transfer_to_principal(&arg).await?;
+    let ledger_symbol = ledger_symbol_snapshot(ledger_id).await;
+    let transfer = transfer_tokens(transfer_arg(test_recipient, amount), ledger_id).await?;
+
+    let mut entry = HistoryEntry::new(TransferHistory::TestTransfer(arg), caller);
+    entry.block_indices = Some(vec![transfer.block_index.clone()]);
+    entry.duplicates = transfer.duplicate.clone().map(|duplicate| vec![duplicate]);
+    entry.ledger_symbol = ledger_symbol;
+    entry.record_fee_correction(transfer.fee_correction.as_ref());
+    record_history(entry);
+
+    match transfer.duplicate {
+        Some(duplicate) if duplicate.handling == OnDuplicate::ReturnError =>
+            Err(already_executed_error(&duplicate)),
+        _ => Ok(transfer.block_index),
+    }
+}
+
+fn transfer_arg(receiving_principal: Principal, amount: u64) -> TransferArg {
+    TransferArg {
+        to: Account {
+            owner: receiving_principal,
+            subaccount: None,
+        },
+        fee: None,
+        memo: None,
+        from_subaccount: treasury_config().main_subaccount,
+        created_at_time: Some(time()),
+        amount: NumTokens::from(amount),
+    }
+}
+
+async fn execute_batch(mut arg: TransferToMultiple, caller: Principal) -> Result<Vec<LegResult>, String> {
+    order_batch(&mut arg);
+    verify_recipients(&batch_recipients(&arg.principals)).await?;
+    let _guard = LedgerGuard::acquire(arg.ledger_id)?;
+    check_batch(&arg).await?;
+    let (legs, _) = run_batch_legs(&arg, caller, 0, false).await;
+    Ok(legs)
+}
+
+// Runs the legs of arg from start_index on, recording the completed ones as a single
+// history entry. With stop_near_limit set it stops before a leg that would push the
+// call past the batch instruction budget and returns the index to resume from; at
+// least one leg always runs so resuming makes progress.
+async fn run_batch_legs(
+    arg: &TransferToMultiple,
+    caller: Principal,
+    start_index: usize,
+    stop_near_limit: bool
+) -> (Vec<LegResult>, Option<usize>) {
+    let ledger_symbol = ledger_symbol_snapshot(arg.ledger_id).await;
+
+    let mut legs = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut fee_correction = None;
+    let mut next_index = None;
+    for (index, principal) in arg.principals.iter().enumerate().skip(start_index) {
+        if stop_near_limit && index > start_index && !leg_fits_instruction_budget() {
+            next_index = Some(index);
+            break;
+        }
+
+        let transfer_amount_arg = transfer_arg(principal.receiving_principal, principal.amount);
+        let transfer = transfer_tokens(transfer_amount_arg, arg.ledger_id).await;
+        if let Ok(TokenTransfer { fee_correction: Some(correction), .. }) = &transfer {
+            fee_correction.get_or_insert(correction.clone());
+        }
+        let result = match transfer {
+            Ok(TokenTransfer { duplicate: Some(duplicate), .. }) => {
+                duplicates.push(duplicate.clone());
+                match duplicate.handling {
+                    OnDuplicate::TreatAsSuccess => Ok(duplicate.duplicate_of),
+                    OnDuplicate::ReturnError => Err(already_executed_error(&duplicate)),
+                }
+            }
+            Ok(transfer) => Ok(transfer.block_index),
+            Err(error) => Err(error),
+        };
+        legs.push(LegResult {
+            receiving_principal: principal.receiving_principal,
+            amount: principal.amount,
+            result,
+        });
+    }
+
+    let completed = legs
+        .iter()
+        .filter(|leg| leg.result.is_ok())
+        .map(|leg| PrincipalTransfer {
+            receiving_principal: leg.receiving_principal,
+            amount: leg.amount,
+        })
+        .collect::<Vec<PrincipalTransfer>>();
+    if !completed.is_empty() || !duplicates.is_empty() {
+        let mut entry = HistoryEntry::new(
+            TransferHistory::TransferToMultiple(TransferToMultiple {
+                principals: completed,
+                ledger_id: arg.ledger_id,
+                category: arg.category.clone(),
+            }),
+            caller
+        );
+        entry.block_indices = Some(
+            legs
+                .iter()
+                .filter_map(|leg| leg.result.clone().ok())
+                .collect()
+        );
+        entry.duplicates = (!duplicates.is_empty()).then_some(duplicates);
+        entry.ledger_symbol = ledger_symbol;
+        entry.record_fee_correction(fee_correction.as_ref());
+        record_history(entry);
+    }
+    (legs, next_index)
+}
+
+fn leg_fits_instruction_budget() -> bool {
+    let per_leg = LEG_INSTRUCTION_ESTIMATE.with(|estimate| estimate.get());
+    ic_cdk::api::call_context_instruction_counter().saturating_add(per_leg) <= batch_instruction_budget()
+}
+
+#[update]
+pub async fn transfer_to_multiple_resumable(
+    mut arg: TransferToMultiple
+) -> Result<BatchProgress, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_multiple(arg.clone())?;
+    check_quarantine(&TransferRequest::TransferToMultiple(arg.clone()), caller)?;
+
+    order_batch(&mut arg);
+    verify_recipients(&batch_recipients(&arg.principals)).await?;
+    let _guard = LedgerGuard::acquire(arg.ledger_id)?;
+    check_batch_funding(&arg).await?;
+    let (legs, next_index) = run_batch_legs(&arg, caller, 0, true).await;
+    let Some(next_index) = next_index else {
+        return Ok(BatchProgress::Completed(legs));
+    };
+
+    let run = BatchRun {
+        arg,
+        caller,
+        next_index: next_index as u64,
+        legs,
+    };
+    let batch_id = BATCH_RUNS.with(|runs| {
+        let mut runs = runs.borrow_mut();
+        let id = runs
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+        runs.insert(id, run);
+        id
+    });
+    Ok(BatchProgress::ResumeNeeded {
+        batch_id,
+        next_index: next_index as u64,
+    })
+}
+
+#[update]
+pub async fn resume_batch(batch_id: BatchRunId) -> Result<BatchProgress, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let pending = BATCH_RUNS.with(|runs| runs.borrow().get(&batch_id)).ok_or(
+        format!("Batch {} not found", batch_id)
+    )?;
+    let remaining_principals = &pending.arg.principals[pending.next_index as usize..];
+    verify_recipients(&batch_recipients(remaining_principals)).await?;
+    let _guard = LedgerGuard::acquire(pending.arg.ledger_id)?;
+    // Re-read under the guard so a concurrent resume of the same batch cannot rerun legs.
+    let mut run = BATCH_RUNS.with(|runs| runs.borrow().get(&batch_id)).ok_or(
+        format!("Batch {} not found", batch_id)
+    )?;
+
+    let start_index = run.next_index as usize;
+    let remaining = TransferToMultiple {
+        principals: run.arg.principals[start_index..].to_vec(),
+        ledger_id: run.arg.ledger_id,
+        category: run.arg.category.clone(),
+    };
+    check_batch_funding(&remaining).await?;
+    let (legs, next_index) = run_batch_legs(&run.arg, caller, start_index, true).await;
+    run.legs.extend(legs);
+
+    match next_index {
+        Some(next_index) => {
+            run.next_index = next_index as u64;
+            BATCH_RUNS.with(|runs| runs.borrow_mut().insert(batch_id, run));
+            Ok(BatchProgress::ResumeNeeded {
+                batch_id,
+                next_index: next_index as u64,
+            })
+        }
+        None => {
+            BATCH_RUNS.with(|runs| runs.borrow_mut().remove(&batch_id));
+            Ok(BatchProgress::Completed(run.legs))
+        }
+    }
+}
+
+fn record_history(entry: HistoryEntry) -> u64 {
+    KNOWN_RECIPIENTS.with(|known| {
+        let mut known = known.borrow_mut();
+        for leg in entry.transfer.legs() {
+            known.insert(leg.receiving_principal, ());
+        }
+    });
+
+    TRANSFER_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let id =
+            history
+                .last_key_value()
+                .map(|(id, _)| id)
+                .unwrap_or_default()
+                .max(last_archived_history_id()) + 1;
+        history.insert(id, entry);
+        id
+    })
+}
+
+fn last_archived_history_id() -> u64 {
+    HISTORY_ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .last_key_value()
+            .map(|(_, range)| range.last_id)
+            .unwrap_or_default()
+    })
+}
+
+pub fn archived_range_for(id: u64) -> Option<ArchivedRange> {
+    HISTORY_ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .range(..=id)
+            .next_back()
+            .map(|(_, range)| range)
+            .filter(|range| id <= range.last_id)
+    })
+}
+
+pub fn backfill_known_recipients() {
+    TRANSFER_HISTORY.with(|history| {
+        KNOWN_RECIPIENTS.with(|known| {
+            let mut known = known.borrow_mut();
+            for (_, entry) in history.borrow().iter() {
+                for leg in entry.transfer.legs() {
+                    known.insert(leg.receiving_principal, ());
+                }
+            }
+        })
+    });
+}
+
+fn matching_quarantine_rule(legs: &[(Principal, u64)]) -> Option<QuarantineRule> {
+    let rule = treasury_config().quarantine_rule?;
+    let triggered = legs.iter().any(|(recipient, amount)| {
+        match rule {
+            QuarantineRule::AmountAbove(threshold) => *amount > threshold,
+            QuarantineRule::NewRecipientAmountAbove(threshold) =>
+                *amount > threshold && !is_known_recipient(*recipient),
+            QuarantineRule::NewRecipientDelay(_) => false,
+        }
+    });
+    triggered.then_some(rule)
+}
+
+fn is_known_recipient(recipient: Principal) -> bool {
+    KNOWN_RECIPIENTS.with(|known| known.borrow().contains_key(&recipient))
+}
+
+// The rule request would be held under: the configured quarantine rule, or
+// NewRecipientDelay when it pays a recipient the treasury has never paid before.
+fn quarantine_hold(request: &TransferRequest) -> Option<QuarantineRule> {
+    let legs = request.legs();
+    if let Some(rule) = matching_quarantine_rule(&legs) {
+        return Some(rule);
+    }
+    let delay_ns = treasury_config().new_recipient_delay_ns?;
+    let has_new_recipient = legs.iter().any(|(recipient, _)| !is_known_recipient(*recipient));
+    has_new_recipient.then_some(QuarantineRule::NewRecipientDelay(delay_ns))
+}
+
+// Quarantines request when quarantine_hold matches; a new-recipient hold can only be
+// released once the delay has passed. The error tells the caller where the transfer
+// went instead.
+fn check_quarantine(request: &TransferRequest, caller: Principal) -> Result<(), String> {
+    match quarantine_hold(request) {
+        None => Ok(()),
+        Some(QuarantineRule::NewRecipientDelay(delay_ns)) => {
+            let release_after_ns = time().saturating_add(delay_ns);
+            let rule = QuarantineRule::NewRecipientDelay(delay_ns);
+            let id = quarantine_transfer(request.clone(), rule, caller, Some(release_after_ns));
+            Err(
+                format!(
+                    "Transfer to a new recipient is held until {} before it can be released (quarantine id {})",
+                    release_after_ns,
+                    id
+                )
+            )
+        }
+        Some(rule) => {
+            let id = quarantine_transfer(request.clone(), rule.clone(), caller, None);
+            Err(
+                format!("Transfer quarantined for review under rule {:?} (quarantine id {})", rule, id)
+            )
+        }
+    }
+}
+
+fn quarantine_transfer(
+    request: TransferRequest,
+    rule: QuarantineRule,
+    caller: Principal,
+    release_after_ns: Option<u64>
+) -> QuarantineId {
+    QUARANTINED_TRANSFERS.with(|quarantined| {
+        let mut quarantined = quarantined.borrow_mut();
+        let id =
+            quarantined
+                .last_key_value()
+                .map(|(id, _)| id)
+                .unwrap_or_default() + 1;
+        quarantined.insert(id, QuarantinedTransfer {
+            request,
+            rule,
+            caller,
+            quarantined_at_ns: time(),
+            status: QuarantineStatus::Pending,
+            release_after_ns,
+        });
+        id
+    })
+}
+
+fn validate_quarantine_rule(rule: &Option<QuarantineRule>) -> Result<(), String> {
+    if matches!(rule, Some(QuarantineRule::NewRecipientDelay(_))) {
+        return Err("Configure the new recipient delay with set_new_recipient_delay".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_quarantine_rule(rule: Option<QuarantineRule>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_quarantine_rule(&rule)?;
+
+    update_treasury_config(|config| {
+        config.quarantine_rule = rule;
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn set_new_recipient_delay(delay_ns: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.new_recipient_delay_ns = delay_ns;
+    });
+    Ok(())
+}
+
+// Marks recipient as known so transfers to it skip the new recipient delay and the
+// NewRecipientAmountAbove rule.
+#[update]
+pub async fn approve_recipient(recipient: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_recipient("recipient", recipient)?;
+    KNOWN_RECIPIENTS.with(|known| {
+        known.borrow_mut().insert(recipient, ());
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn release_quarantined_transfer(id: QuarantineId) -> Result<Vec<BlockIndex>, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let release_after_ns = QUARANTINED_TRANSFERS.with(|quarantined| {
+        quarantined.borrow().get(&id).and_then(|transfer| transfer.release_after_ns)
+    });
+    if let Some(release_after_ns) = release_after_ns {
+        if time() < release_after_ns {
+            return Err(
+                format!("Quarantined transfer {} cannot be released before {}", id, release_after_ns)
+            );
+        }
+    }
+
+    let quarantined = take_pending_quarantined_transfer(id)?;
+    let result = match quarantined.request {
+        TransferRequest::TransferToPrincipal(arg) =>
+            execute_transfer_to_principal(arg, quarantined.caller, None).await.map(|block_index| {
+                vec![block_index]
+            }),
+        TransferRequest::TransferToMultiple(arg) =>
+            execute_transfer_to_multiple(arg, quarantined.caller).await,
+    };
+    set_quarantine_status(id, QuarantineStatus::Released(result.clone()));
+    result
+}
+
+#[update]
+pub async fn reject_quarantined_transfer(id: QuarantineId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    take_pending_quarantined_transfer(id)?;
+    set_quarantine_status(id, QuarantineStatus::Rejected);
+    Ok(())
+}
+
+fn take_pending_quarantined_transfer(id: QuarantineId) -> Result<QuarantinedTransfer, String> {
+    QUARANTINED_TRANSFERS.with(|quarantined| {
+        let mut quarantined = quarantined.borrow_mut();
+        let mut transfer = quarantined
+            .get(&id)
+            .ok_or(format!("Quarantined transfer {} not found", id))?;
+        if !matches!(transfer.status, QuarantineStatus::Pending) {
+            return Err(format!("Quarantined transfer {} has already been handled", id));
+        }
+        // Mark as handled before awaiting so a concurrent release cannot execute it twice.
+        transfer.status = QuarantineStatus::Released(Err("Release in progress".to_string()));
+        quarantined.insert(id, transfer.clone());
+        Ok(transfer)
+    })
+}
+
+fn set_quarantine_status(id: QuarantineId, status: QuarantineStatus) {
+    QUARANTINED_TRANSFERS.with(|quarantined| {
+        let mut quarantined = quarantined.borrow_mut();
+        if let Some(mut transfer) = quarantined.get(&id) {
+            transfer.status = status;
+            quarantined.insert(id, transfer);
+        }
+    });
+}
+
+fn validate_recurring_transfer(arg: &TransferToPrincipal, interval_ns: u64) -> Result<(), String> {
+    validate_transfer_to_principal(arg.clone())?;
+    if interval_ns < MIN_RECURRING_INTERVAL_NS {
+        return Err(
+            format!("Recurring interval must be at least {} nanoseconds", MIN_RECURRING_INTERVAL_NS)
+        );
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn create_recurring_transfer(
+    arg: TransferToPrincipal,
+    interval_ns: u64,
+    first_execution_ns: Option<u64>
+) -> Result<RecurringId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_recurring_transfer(&arg, interval_ns)?;
+    // A quarantined payment runs once on release, so a job that would be held is refused
+    // instead of quarantining only its first payment.
+    if let Some(rule) = quarantine_hold(&TransferRequest::TransferToPrincipal(arg.clone())) {
+        return Err(
+            format!(
+                "Recurring transfer would be quarantined under rule {:?}; approve the recipient or change the rule first",
+                rule
+            )
+        );
+    }
+
+    let now = time();
+    let next_execution_ns = first_execution_ns.unwrap_or(now + interval_ns);
+    if next_execution_ns < now {
+        return Err("First execution time must not be in the past".to_string());
+    }
+
+    let recurring = RecurringTransfer {
+        transfer: arg,
+        interval_ns,
+        next_execution_ns,
+        active: true,
+        consecutive_shortfalls: None,
+        needs_attention: None,
+        events: None,
+        caller: Some(caller),
+    };
+    let id = RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let mut recurring_transfers = recurring_transfers.borrow_mut();
+        let id =
+            recurring_transfers
+                .last_key_value()
+                .map(|(id, _)| id)
+                .unwrap_or_default() + 1;
+        recurring_transfers.insert(id, recurring.clone());
+        id
+    });
+    arm_recurring_timer(id, &recurring);
+    Ok(id)
+}
+
+#[update]
+pub async fn cancel_recurring_transfer(id: RecurringId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let mut recurring_transfers = recurring_transfers.borrow_mut();
+        let mut recurring = recurring_transfers
+            .get(&id)
+            .ok_or(format!("Recurring transfer {} not found", id))?;
+        if !recurring.active {
+            return Err(format!("Recurring transfer {} is already cancelled", id));
+        }
+        recurring.active = false;
+        recurring_transfers.insert(id, recurring);
+        Ok(())
+    })?;
+
+    if let Some(timer_id) = RECURRING_TIMERS.with(|timers| timers.borrow_mut().remove(&id)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn schedule_transfer(arg: TransferToPrincipal, execute_at_ns: u64) -> Result<ScheduleId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_principal(arg.clone())?;
+    let request = TransferRequest::TransferToPrincipal(arg);
+    check_quarantine(&request, caller)?;
+    insert_scheduled_transfer(request, execute_at_ns, caller, false)
+}
+
+#[update]
+pub async fn schedule_batch(arg: TransferToMultiple, execute_at_ns: u64) -> Result<ScheduleId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_multiple(arg.clone())?;
+    let request = TransferRequest::TransferToMultiple(arg);
+    check_quarantine(&request, caller)?;
+    insert_scheduled_transfer(request, execute_at_ns, caller, false)
+}
+
+// Returns as soon as the transfer is queued; poll get_transfer_status with the
+// tracking id for the outcome.
+#[update]
+pub async fn transfer_to_principal_async(arg: TransferToPrincipal) -> Result<TrackingId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_principal(arg.clone())?;
+    let request = TransferRequest::TransferToPrincipal(arg);
+    check_quarantine(&request, caller)?;
+    insert_scheduled_transfer(request, time(), caller, true)
+}
+
+#[update]
+pub async fn transfer_to_multiple_async(arg: TransferToMultiple) -> Result<TrackingId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_transfer_to_multiple(arg.clone())?;
+    let request = TransferRequest::TransferToMultiple(arg);
+    check_quarantine(&request, caller)?;
+    insert_scheduled_transfer(request, time(), caller, true)
+}
+
+#[update]
+pub async fn cancel_scheduled_transfer(id: ScheduleId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    cancel_schedule(id)
+}
+
+#[update]
+pub async fn cancel_scheduled_transfers(
+    ids: Vec<ScheduleId>
+) -> Vec<(ScheduleId, Result<(), String>)> {
+    let caller = ic_cdk::caller();
+    let is_controller = is_controller(caller).await;
+
+    ids.into_iter()
+        .map(|id| {
+            let result = if is_controller {
+                cancel_schedule(id)
+            } else {
+                Err("Caller is not a controller".to_string())
+            };
+            (id, result)
+        })
+        .collect()
+}
+
+fn cancel_schedule(id: ScheduleId) -> Result<(), String> {
+    SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        let mut scheduled_transfers = scheduled_transfers.borrow_mut();
+        let mut scheduled = scheduled_transfers
+            .get(&id)
+            .ok_or(format!("Scheduled transfer {} not found", id))?;
+        if !matches!(scheduled.status, ScheduleStatus::Pending | ScheduleStatus::AwaitingReview(_)) {
+            return Err(format!("Scheduled transfer {} is no longer pending", id));
+        }
+        scheduled.status = ScheduleStatus::Cancelled;
+        scheduled_transfers.insert(id, scheduled);
+        Ok(())
+    })?;
+
+    if let Some(timer_id) = SCHEDULE_TIMERS.with(|timers| timers.borrow_mut().remove(&id)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    Ok(())
+}
+
+fn insert_scheduled_transfer(
+    payload: TransferRequest,
+    execute_at_ns: u64,
+    caller: Principal,
+    immediate: bool
+) -> Result<ScheduleId, String> {
+    if execute_at_ns < time() {
+        return Err("Execution time must not be in the past".to_string());
+    }
+
+    let scheduled = ScheduledTransfer {
+        payload,
+        execute_at_ns,
+        status: ScheduleStatus::Pending,
+        caller: Some(caller),
+        immediate: Some(immediate),
+    };
+    let id = SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        let mut scheduled_transfers = scheduled_transfers.borrow_mut();
+        let id =
+            scheduled_transfers
+                .last_key_value()
+                .map(|(id, _)| id)
+                .unwrap_or_default() + 1;
+        scheduled_transfers.insert(id, scheduled.clone());
+        id
+    });
+    arm_schedule_timer(id, &scheduled);
+    Ok(id)
+}
+
+// Lists every armed timer plus every persisted job that should have one but does not.
+pub fn active_timers() -> Vec<TimerInfo> {
+    let armed_recurring = RECURRING_TIMERS.with(|timers| {
+        timers.borrow().keys().copied().collect::<BTreeSet<RecurringId>>()
+    });
+    let armed_schedules = SCHEDULE_TIMERS.with(|timers| {
+        timers.borrow().keys().copied().collect::<BTreeSet<ScheduleId>>()
+    });
+    let mut timers = job_timers(&armed_recurring, &armed_schedules);
+
+    timers.push(
+        interval_timer_info(
+            TimerKind::FeeSampling,
+            FEE_SAMPLING_TIMER.with(|timer| timer.get()),
+            FEE_SAMPLE_INTERVAL,
+            "Fee sampling"
+        )
+    );
+    timers.push(
+        interval_timer_info(
+            TimerKind::HistoryArchiving,
+            HISTORY_ARCHIVING_TIMER.with(|timer| timer.get()),
+            HISTORY_ARCHIVE_INTERVAL,
+            "History archiving"
+        )
+    );
+    timers
+}
+
+// Compares the persisted recurring and scheduled jobs against the ids that have a
+// timer armed. Kept apart from the timer maps so an upgrade can be replayed in tests.
+fn job_timers(
+    armed_recurring: &BTreeSet<RecurringId>,
+    armed_schedules: &BTreeSet<ScheduleId>
+) -> Vec<TimerInfo> {
+    let mut timers = Vec::new();
+
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let recurring_transfers = recurring_transfers.borrow();
+        for id in armed_recurring {
+            let recurring = recurring_transfers.get(id);
+            timers.push(TimerInfo {
+                kind: TimerKind::RecurringTransfer,
+                id: Some(*id),
+                next_fire_ns: recurring.as_ref().map(|recurring| recurring.next_execution_ns),
+                mismatch: match recurring {
+                    None => Some("Armed for a recurring transfer that no longer exists".to_string()),
+                    Some(recurring) if !recurring.active =>
+                        Some("Armed for an inactive recurring transfer".to_string()),
+                    Some(_) => None,
+                },
+            });
+        }
+        for (id, recurring) in recurring_transfers.iter() {
+            if recurring.active && !armed_recurring.contains(&id) {
+                timers.push(TimerInfo {
+                    kind: TimerKind::RecurringTransfer,
+                    id: Some(id),
+                    next_fire_ns: Some(recurring.next_execution_ns),
+                    mismatch: Some("Active recurring transfer has no armed timer".to_string()),
+                });
+            }
+        }
+    });
+
+    SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        let scheduled_transfers = scheduled_transfers.borrow();
+        for id in armed_schedules {
+            let scheduled = scheduled_transfers.get(id);
+            timers.push(TimerInfo {
+                kind: TimerKind::ScheduledTransfer,
+                id: Some(*id),
+                next_fire_ns: scheduled.as_ref().map(|scheduled| scheduled.execute_at_ns),
+                mismatch: match scheduled {
+                    None => Some("Armed for a scheduled transfer that no longer exists".to_string()),
+                    Some(scheduled) if !matches!(scheduled.status, ScheduleStatus::Pending) =>
+                        Some("Armed for a scheduled transfer that is no longer pending".to_string()),
+                    Some(_) => None,
+                },
+            });
+        }
+        for (id, scheduled) in scheduled_transfers.iter() {
+            if matches!(scheduled.status, ScheduleStatus::Pending) && !armed_schedules.contains(&id) {
+                timers.push(TimerInfo {
+                    kind: TimerKind::ScheduledTransfer,
+                    id: Some(id),
+                    next_fire_ns: Some(scheduled.execute_at_ns),
+                    mismatch: Some("Pending scheduled transfer has no armed timer".to_string()),
+                });
+            }
+        }
+    });
+    timers
+}
+
+fn interval_timer_info(
+    kind: TimerKind,
+    timer: Option<(TimerId, u64)>,
+    interval: Duration,
+    name: &str
+) -> TimerInfo {
+    let interval_ns = interval.as_nanos() as u64;
+    match timer {
+        Some((_, started_at)) => {
+            let elapsed_intervals = time().saturating_sub(started_at) / interval_ns;
+            TimerInfo {
+                kind,
+                id: None,
+                next_fire_ns: Some(started_at + (elapsed_intervals + 1) * interval_ns),
+                mismatch: None,
+            }
+        }
+        None =>
+            TimerInfo {
+                kind,
+                id: None,
+                next_fire_ns: None,
+                mismatch: Some(format!("{} timer is not armed", name)),
+            },
+    }
+}
+
+pub fn rearm_scheduled_timers() {
+    let pending = SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        scheduled_transfers
+            .borrow()
+            .iter()
+            .filter(|(_, scheduled)| matches!(scheduled.status, ScheduleStatus::Pending))
+            .collect::<Vec<(ScheduleId, ScheduledTransfer)>>()
+    });
+    for (id, scheduled) in pending.iter() {
+        arm_schedule_timer(*id, scheduled);
+    }
+    ic_cdk::println!("Re-armed {} scheduled transfer timers", pending.len());
+}
+
+// True when exactly the active recurring and pending scheduled jobs have armed timers
+// and fee sampling is running. Any mismatch is logged.
+pub fn timers_match_persisted_jobs() -> bool {
+    let mismatches = active_timers()
+        .into_iter()
+        .filter(|timer| timer.mismatch.is_some())
+        .collect::<Vec<TimerInfo>>();
+    for timer in &mismatches {
+        ic_cdk::println!(
+            "Timer mismatch for {:?} {:?}: {}",
+            timer.kind,
+            timer.id,
+            timer.mismatch.clone().unwrap_or_default()
+        );
+    }
+    mismatches.is_empty()
+}
+
+fn arm_schedule_timer(id: ScheduleId, scheduled: &ScheduledTransfer) {
+    arm_schedule_timer_in(id, Duration::from_nanos(scheduled.execute_at_ns.saturating_sub(time())));
+}
+
+fn arm_schedule_timer_in(id: ScheduleId, delay: Duration) {
+    let timer_id = ic_cdk_timers::set_timer(delay, move || {
+        ic_cdk::spawn(execute_scheduled_transfer(id));
+    });
+    SCHEDULE_TIMERS.with(|timers| timers.borrow_mut().insert(id, timer_id));
+}
+
+fn set_schedule_status(id: ScheduleId, status: ScheduleStatus) {
+    SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        let mut scheduled_transfers = scheduled_transfers.borrow_mut();
+        if let Some(mut scheduled) = scheduled_transfers.get(&id) {
+            scheduled.status = status;
+            scheduled_transfers.insert(id, scheduled);
+        }
+    });
+}
+
+async fn execute_scheduled_transfer(id: ScheduleId) {
+    SCHEDULE_TIMERS.with(|timers| timers.borrow_mut().remove(&id));
+
+    let Some(scheduled) = SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+        scheduled_transfers.borrow().get(&id)
+    }) else {
+        return;
+    };
+    if !matches!(scheduled.status, ScheduleStatus::Pending) {
+        return;
+    }
+    if !scheduled.immediate.unwrap_or_default() {
+        if let Err(error) = check_signer_quorum() {
+            ic_cdk::println!("Scheduled transfer {} held for review: {}", id, error);
+            set_schedule_status(id, ScheduleStatus::AwaitingReview(error));
+            return;
+        }
+    }
+    if !run_scheduled_transfer(id, scheduled).await {
+        arm_schedule_timer_in(id, LEDGER_BUSY_RETRY_DELAY);
+    }
+}
+
+// Returns false, with the job back to Pending and nothing sent, when another transfer
+// held the ledger; the caller decides when to try again.
+async fn run_scheduled_transfer(id: ScheduleId, scheduled: ScheduledTransfer) -> bool {
+    set_schedule_status(id, ScheduleStatus::Executing);
+
+    let ledger_id = scheduled.payload.ledger_id();
+    let caller = scheduled.caller.unwrap_or_else(ic_cdk::id);
+    let status = match scheduled.payload {
+        TransferRequest::TransferToPrincipal(arg) => {
+            let receiving_principal = arg.receiving_principal;
+            let amount = arg.amount;
+            let result = execute_transfer_to_principal(arg, caller, None).await;
+            if is_ledger_busy(&result, ledger_id) {
+                set_schedule_status(id, ScheduleStatus::Pending);
+                return false;
+            }
+            ScheduleStatus::Executed(vec![LegResult { receiving_principal, amount, result }])
+        }
+        TransferRequest::TransferToMultiple(arg) => {
+            let result = execute_batch(arg, caller).await;
+            if is_ledger_busy(&result, ledger_id) {
+                set_schedule_status(id, ScheduleStatus::Pending);
+                return false;
+            }
+            match result {
+                Ok(legs) => ScheduleStatus::Executed(legs),
+                Err(error) => ScheduleStatus::Failed(error),
+            }
+        }
+    };
+    set_schedule_status(id, status);
+    true
+}
+
+pub fn rearm_recurring_timers() {
+    let active = RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers
+            .borrow()
+            .iter()
+            .filter(|(_, recurring)| recurring.active)
+            .collect::<Vec<(RecurringId, RecurringTransfer)>>()
+    });
+    for (id, recurring) in active.iter() {
+        arm_recurring_timer(*id, recurring);
+    }
+    ic_cdk::println!("Re-armed {} recurring transfer timers", active.len());
+}
+
+// time() is the block time of the executing round, so a call answered within the same
+// round measures 0 and everything else is rounded to whole rounds (about a second on
+// most subnets). The figures show which ledgers take many rounds, not exact latency.
+fn record_ledger_latency(ledger_id: Principal, started_at: u64) {
+    let elapsed = time().saturating_sub(started_at);
+    LEDGER_LATENCY.with(|latency| {
+        let mut latency = latency.borrow_mut();
+        let (stats, total_ns) = latency.entry(ledger_id).or_default();
+        stats.min_ns = if stats.calls == 0 { elapsed } else { stats.min_ns.min(elapsed) };
+        stats.max_ns = stats.max_ns.max(elapsed);
+        stats.calls += 1;
+        *total_ns += elapsed as u128;
+        stats.avg_ns = (*total_ns / (stats.calls as u128)) as u64;
+    });
+}
+
+fn record_error(kind: ErrorKind, ledger_id: Principal, arg: &TransferArg, message: String) {
+    let record = ErrorRecord {
+        timestamp_ns: time(),
+        ledger_id,
+        recipient: arg.to.owner,
+        amount: arg.amount.clone(),
+        kind,
+        message,
+    };
+    RECENT_ERRORS.with(|errors| {
+        let mut errors = errors.borrow_mut();
+        let id = errors
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+        errors.insert(id, record);
+        while errors.len() > MAX_ERROR_RECORDS {
+            let Some((oldest, _)) = errors.first_key_value() else {
+                break;
+            };
+            errors.remove(&oldest);
+        }
+    });
+}
+
+fn update_ledger_stats(ledger_id: Principal, f: impl FnOnce(&mut LedgerStats)) {
+    LEDGER_STATS.with(|stats| f(stats.borrow_mut().entry(ledger_id).or_default()));
+}
+
+pub fn ledger_stats(ledger_id: Principal) -> LedgerStats {
+    LEDGER_STATS.with(|stats| stats.borrow().get(&ledger_id).cloned().unwrap_or_default())
+}
+
+pub fn ledger_latency(ledger_id: Principal) -> LatencyStats {
+    LEDGER_LATENCY.with(|latency| {
+        latency
+            .borrow()
+            .get(&ledger_id)
+            .map(|(stats, _)| stats.clone())
+            .unwrap_or_default()
+    })
+}
+
+pub fn start_fee_sampling() {
+    let timer_id = ic_cdk_timers::set_timer_interval(FEE_SAMPLE_INTERVAL, || {
+        ic_cdk::spawn(sample_ledger_fees());
+    });
+    FEE_SAMPLING_TIMER.with(|timer| timer.set(Some((timer_id, time()))));
+}
+
+// Always armed; rounds do nothing until history_archive is configured.
+pub fn start_history_archiving() {
+    let timer_id = ic_cdk_timers::set_timer_interval(HISTORY_ARCHIVE_INTERVAL, || {
+        ic_cdk::spawn(archive_history());
+    });
+    HISTORY_ARCHIVING_TIMER.with(|timer| timer.set(Some((timer_id, time()))));
+}
+
+// Clears HISTORY_ARCHIVING_IN_FLIGHT however archive_history exits, including a trap
+// in the callback of the archive call.
+struct ArchivingGuard;
+
+impl ArchivingGuard {
+    fn acquire() -> Option<Self> {
+        if HISTORY_ARCHIVING_IN_FLIGHT.with(|in_flight| in_flight.replace(true)) {
+            return None;
+        }
+        Some(ArchivingGuard)
+    }
+}
+
+impl Drop for ArchivingGuard {
+    fn drop(&mut self) {
+        HISTORY_ARCHIVING_IN_FLIGHT.with(|in_flight| in_flight.set(false));
+    }
+}
+
+// Takes entries until the batch would exceed max_entries or max_bytes of encoded
+// entries. The first entry is always taken: it was recorded from a message under the
+// same 2MB limit, and skipping it would stall archiving for good.
+fn archive_batch(
+    entries: impl Iterator<Item = (u64, HistoryEntry)>,
+    max_entries: usize,
+    max_bytes: usize
+) -> Vec<(u64, HistoryEntry)> {
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0usize;
+    for (id, entry) in entries.take(max_entries) {
+        let entry_bytes = std::mem::size_of::<u64>() + entry.to_bytes().len();
+        if !batch.is_empty() && batch_bytes + entry_bytes > max_bytes {
+            break;
+        }
+        batch_bytes += entry_bytes;
+        batch.push((id, entry));
+    }
+    batch
+}
+
+// Moves up to MAX_ARCHIVE_BATCH of the oldest entries past the threshold per round,
+// capped at MAX_ARCHIVE_BATCH_BYTES so the call fits in one message. Entries are only
+// removed locally once the archive has accepted them; if the call fails they stay and
+// the next round retries. Entries without a timestamp predate every timestamped one and
+// are archived as old.
+async fn archive_history() {
+    let Some(archive) = treasury_config().history_archive else {
+        return;
+    };
+    let Some(_guard) = ArchivingGuard::acquire() else {
+        return;
+    };
+
+    let cutoff_ns = time().saturating_sub(archive.older_than_ns);
+    let entries = TRANSFER_HISTORY.with(|history| {
+        archive_batch(
+            history
+                .borrow()
+                .iter()
+                .take_while(|(_, entry)| entry.timestamp.is_none_or(|timestamp| timestamp < cutoff_ns)),
+            MAX_ARCHIVE_BATCH,
+            MAX_ARCHIVE_BATCH_BYTES
+        )
+    });
+    if let (Some((first_id, _)), Some((last_id, _))) = (entries.first(), entries.last()) {
+        let (first_id, last_id) = (*first_id, *last_id);
+        let result = ic_cdk::call::<(Vec<(u64, HistoryEntry)>,), ()>(
+            archive.canister_id,
+            "append_history",
+            (entries.clone(),)
+        ).await;
+        match result {
+            Ok(()) => {
+                TRANSFER_HISTORY.with(|history| {
+                    let mut history = history.borrow_mut();
+                    for (id, _) in &entries {
+                        history.remove(id);
+                    }
+                });
+                HISTORY_ARCHIVES.with(|archives| {
+                    archives.borrow_mut().insert(first_id, ArchivedRange {
+                        last_id,
+                        canister_id: archive.canister_id,
+                        archived_at_ns: time(),
+                    })
+                });
+            }
+            Err(error) => {
+                ic_cdk::println!(
+                    "Archiving history {}..={} to {} failed: {:?}",
+                    first_id,
+                    last_id,
+                    archive.canister_id,
+                    error
+                );
+            }
+        }
+    }
+}
+
+fn validate_history_archive(archive: &Option<ArchiveConfig>) -> Result<(), String> {
+    if let Some(archive) = archive {
+        if archive.older_than_ns == 0 {
+            return Err("older_than_ns: must be greater than 0".to_string());
+        }
+        if archive.canister_id == ic_cdk::id() || archive.canister_id == Principal::anonymous() {
+            return Err("canister_id: invalid archive canister".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Aggregates computed from local history (budgets, caller stats, fees by category) stop
+// counting entries once they are archived, so older_than_ns should exceed the longest
+// budget period.
+#[update]
+pub async fn set_history_archive(archive: Option<ArchiveConfig>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_history_archive(&archive)?;
+
+    update_treasury_config(|config| {
+        config.history_archive = archive;
+    });
+    Ok(())
+}
+
+// Samples every ledger with a stored config. A ledger that fails to answer simply
+// gets no sample for that round.
+async fn sample_ledger_fees() {
+    let ledger_ids = LEDGER_CONFIGS.with(|configs| {
+        configs
+            .borrow()
+            .iter()
+            .map(|(ledger_id, _)| ledger_id)
+            .collect::<Vec<Principal>>()
+    });
+
+    for ledger_id in ledger_ids {
+        let Ok(fee) = get_ledger_fee(ledger_id).await else {
+            continue;
+        };
+        let Ok(fee) = u64::try_from(fee.0) else {
+            continue;
+        };
+
+        let sampled_at = time();
+        FEE_HISTORY.with(|fee_history| {
+            let mut fee_history = fee_history.borrow_mut();
+            let mut samples = fee_history.get(&ledger_id).unwrap_or_default();
+            samples.0.push((sampled_at, fee));
+            if samples.0.len() > MAX_FEE_SAMPLES {
+                let excess = samples.0.len() - MAX_FEE_SAMPLES;
+                samples.0.drain(..excess);
+            }
+            fee_history.insert(ledger_id, samples);
+        });
+    }
+}
+
+fn arm_recurring_timer(id: RecurringId, recurring: &RecurringTransfer) {
+    arm_recurring_timer_in(id, Duration::from_nanos(recurring.next_execution_ns.saturating_sub(time())));
+}
+
+fn arm_recurring_timer_in(id: RecurringId, delay: Duration) {
+    let timer_id = ic_cdk_timers::set_timer(delay, move || {
+        ic_cdk::spawn(execute_recurring_transfer(id));
+    });
+    RECURRING_TIMERS.with(|timers| timers.borrow_mut().insert(id, timer_id));
+}
+
+async fn execute_recurring_transfer(id: RecurringId) {
+    RECURRING_TIMERS.with(|timers| timers.borrow_mut().remove(&id));
+
+    let Some(mut recurring) = RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers.borrow().get(&id)
+    }) else {
+        return;
+    };
+    if !recurring.active {
+        return;
+    }
+    if let Err(error) = check_signer_quorum() {
+        ic_cdk::println!("Recurring transfer {} paused for review: {}", id, error);
+        recurring.active = false;
+        recurring.needs_attention = Some(true);
+        let mut events = recurring.events.take().unwrap_or_default();
+        events.push(RecurringEvent::PausedForQuorum { at_ns: time(), message: error });
+        if events.len() > MAX_RECURRING_EVENTS {
+            events.drain(..events.len() - MAX_RECURRING_EVENTS);
+        }
+        recurring.events = Some(events);
+        RECURRING_TRANSFERS.with(|recurring_transfers| {
+            recurring_transfers.borrow_mut().insert(id, recurring);
+        });
+        return;
+    }
+
+    let now = time();
+    let due_ns = recurring.next_execution_ns;
+    while recurring.next_execution_ns <= now {
+        recurring.next_execution_ns += recurring.interval_ns;
+    }
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers.borrow_mut().insert(id, recurring.clone());
+    });
+    arm_recurring_timer(id, &recurring);
+
+    let ledger_id = recurring.transfer.ledger_id;
+    let caller = recurring.caller.unwrap_or(ic_cdk::id());
+    let result = execute_transfer_to_principal(recurring.transfer, caller, Some(id)).await;
+    if is_ledger_busy(&result, ledger_id) {
+        // Not a missed cycle: put the due time back and try again shortly. The next cycle
+        // is still computed from due_ns, so the job keeps its phase.
+        retry_recurring_transfer(id, due_ns);
+        return;
+    }
+    if let Err(error) = &result {
+        ic_cdk::println!("Recurring transfer {} failed: {}", id, error);
+    }
+    record_recurring_outcome(id, &result);
+}
+
+fn retry_recurring_transfer(id: RecurringId, due_ns: u64) {
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let mut recurring_transfers = recurring_transfers.borrow_mut();
+        if let Some(mut recurring) = recurring_transfers.get(&id) {
+            recurring.next_execution_ns = due_ns;
+            recurring_transfers.insert(id, recurring);
+        }
+    });
+    if let Some(timer_id) = RECURRING_TIMERS.with(|timers| timers.borrow_mut().remove(&id)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    arm_recurring_timer_in(id, LEDGER_BUSY_RETRY_DELAY);
+}
+
+// A shortfall skips the cycle; recurring_shortfall_limit of them in a row pause the job
+// and flag it until resume_recurring_transfer. Any other outcome resets the count.
+fn record_recurring_outcome(id: RecurringId, result: &Result<BlockIndex, String>) {
+    let Some(limit) = treasury_config().recurring_shortfall_limit else {
+        return;
+    };
+    let Some(mut recurring) = RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers.borrow().get(&id)
+    }) else {
+        return;
+    };
+
+    let now = time();
+    let mut events = recurring.events.take().unwrap_or_default();
+    match result {
+        Err(error) if error.starts_with("Insufficient operational balance") => {
+            let shortfalls = recurring.consecutive_shortfalls.unwrap_or_default() + 1;
+            recurring.consecutive_shortfalls = Some(shortfalls);
+            events.push(RecurringEvent::SkippedForShortfall {
+                at_ns: now,
+                message: error.clone(),
+            });
+            if shortfalls >= limit && recurring.active {
+                recurring.active = false;
+                recurring.needs_attention = Some(true);
+                events.push(RecurringEvent::AutoPaused {
+                    at_ns: now,
+                    consecutive_shortfalls: shortfalls,
+                });
+                if let Some(timer_id) = RECURRING_TIMERS.with(|timers| timers.borrow_mut().remove(&id)) {
+                    ic_cdk_timers::clear_timer(timer_id);
+                }
+            }
+        }
+        _ => {
+            recurring.consecutive_shortfalls = None;
+        }
+    }
+    if events.len() > MAX_RECURRING_EVENTS {
+        events.drain(..events.len() - MAX_RECURRING_EVENTS);
+    }
+    recurring.events = (!events.is_empty()).then_some(events);
+
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        recurring_transfers.borrow_mut().insert(id, recurring);
+    });
+}
+
+fn validate_recurring_shortfall_limit(limit: Option<u32>) -> Result<(), String> {
+    if limit == Some(0) {
+        return Err("limit: must be greater than 0".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_recurring_shortfall_limit(limit: Option<u32>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_recurring_shortfall_limit(limit)?;
+
+    update_treasury_config(|config| {
+        config.recurring_shortfall_limit = limit;
+    });
+    Ok(())
+}
+
+// Reactivates a recurring transfer that paused itself, starting a fresh cycle from now.
+// Resuming is the manual review, so it does not check the signer quorum.
+#[update]
+pub async fn resume_recurring_transfer(id: RecurringId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let recurring = RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let mut recurring_transfers = recurring_transfers.borrow_mut();
+        let mut recurring = recurring_transfers
+            .get(&id)
+            .ok_or(format!("Recurring transfer {} not found", id))?;
+        if !recurring.needs_attention.unwrap_or_default() {
+            return Err(format!("Recurring transfer {} was not paused for review", id));
+        }
+        recurring.active = true;
+        recurring.needs_attention = None;
+        recurring.consecutive_shortfalls = None;
+        recurring.next_execution_ns = time().saturating_add(recurring.interval_ns);
+        recurring_transfers.insert(id, recurring.clone());
+        Ok(recurring)
+    })?;
+
+    arm_recurring_timer(id, &recurring);
+    Ok(())
+}
+
+fn check_signer_quorum() -> Result<(), String> {
+    let Some(quorum) = treasury_config().signer_quorum else {
+        return Ok(());
+    };
+    let since = time().saturating_sub(quorum.window_ns);
+    // Heartbeats of principals that are no longer controllers do not count.
+    let online = SIGNER_HEARTBEATS.with(|heartbeats| {
+        heartbeats
+            .borrow()
+            .iter()
+            .filter(|(signer, at_ns)| *at_ns >= since && ic_cdk::api::is_controller(signer))
+            .count() as u32
+    });
+    if online < quorum.min_signers {
+        return Err(
+            format!(
+                "Signer quorum not met: {} of {} required signers sent a heartbeat in the last {} ns",
+                online,
+                quorum.min_signers,
+                quorum.window_ns
+            )
+        );
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn heartbeat() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let now = time();
+    SIGNER_HEARTBEATS.with(|heartbeats| heartbeats.borrow_mut().insert(caller, now));
+    Ok(now)
+}
+
+fn validate_signer_quorum(quorum: &Option<SignerQuorum>) -> Result<(), String> {
+    if let Some(quorum) = quorum {
+        if quorum.min_signers == 0 {
+            return Err("min_signers: must be greater than 0".to_string());
+        }
+        if quorum.window_ns == 0 {
+            return Err("window_ns: must be greater than 0".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_signer_quorum(quorum: Option<SignerQuorum>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_signer_quorum(&quorum)?;
+
+    update_treasury_config(|config| {
+        config.signer_quorum = quorum;
+    });
+    Ok(())
+}
+
+// Executes a scheduled transfer that was held back for lack of signer quorum, now that
+// a controller has reviewed it.
+#[update]
+pub async fn release_scheduled_transfer(id: ScheduleId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let scheduled = SCHEDULED_TRANSFERS.with(|scheduled_transfers| scheduled_transfers.borrow().get(&id)).ok_or(
+        format!("Scheduled transfer {} not found", id)
+    )?;
+    let ScheduleStatus::AwaitingReview(reason) = scheduled.status.clone() else {
+        return Err(format!("Scheduled transfer {} is not awaiting review", id));
+    };
+    let ledger_id = scheduled.payload.ledger_id();
+    if !run_scheduled_transfer(id, scheduled).await {
+        set_schedule_status(id, ScheduleStatus::AwaitingReview(reason));
+        return Err(ledger_busy_error(ledger_id));
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_reserve_amount(ledger_id: Principal, reserve_amount: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+
+    update_ledger_config(ledger_id, |config| {
+        config.reserve_amount = reserve_amount;
+    });
+    Ok(())
+}
+
+#[update(guard = "check_read_access")]
+pub async fn get_operational_balance(ledger_id: Principal) -> Result<NumTokens, String> {
+    get_operational_tokens_balance(ledger_id).await
+}
+
+#[update(guard = "check_read_access")]
+pub async fn get_reserve_balance(ledger_id: Principal) -> Result<NumTokens, String> {
+    let balance = get_tokens_balance(ledger_id).await?;
+    let reserve = NumTokens::from(reserve_amount(ledger_id));
+    Ok(balance.min(reserve))
+}
+
+// One entry per requested ledger, in order. Ledgers are queried one after another and a
+// reject or error from one is reported in its own entry instead of failing the call.
+// Every ledger costs the treasury a call, so the list is capped and callers are limited
+// to controllers and observers.
+#[update]
+pub async fn get_balances(
+    ledger_ids: Vec<Principal>
+) -> Result<Vec<(Principal, Result<NumTokens, String>)>, String> {
+    check_controller_or_observer()?;
+    if ledger_ids.len() > MAX_BALANCE_LEDGERS {
+        return Err(format!("At most {} ledgers can be queried at once", MAX_BALANCE_LEDGERS));
+    }
+    Ok(collect_balances(ledger_ids, get_tokens_balance).await)
+}
+
+async fn collect_balances<F, Fut>(
+    ledger_ids: Vec<Principal>,
+    fetch_balance: F
+) -> Vec<(Principal, Result<NumTokens, String>)>
+    where F: Fn(Principal) -> Fut, Fut: std::future::Future<Output = Result<NumTokens, String>>
+{
+    let mut balances = Vec::new();
+    for ledger_id in ledger_ids {
+        let balance = match validate_ledger_id(ledger_id) {
+            Ok(()) => fetch_balance(ledger_id).await,
+            Err(error) => Err(error),
+        };
+        balances.push((ledger_id, balance));
+    }
+    balances
+}
+
+fn validate_ledger_methods(
+    balance_method: &Option<String>,
+    transfer_method: &Option<String>
+) -> Result<(), String> {
+    for (field, method) in [("balance_method", balance_method), ("transfer_method", transfer_method)] {
+        if method.as_ref().is_some_and(|method| method.trim().is_empty()) {
+            return Err(format!("{}: method name must not be empty", field));
+        }
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_ledger_methods(
+    ledger_id: Principal,
+    balance_method: Option<String>,
+    transfer_method: Option<String>
+) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    validate_ledger_methods(&balance_method, &transfer_method)?;
+
+    update_ledger_config(ledger_id, |config| {
+        config.balance_method = balance_method;
+        config.transfer_method = transfer_method;
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn set_ledger_fee(ledger_id: Principal, fee: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    update_ledger_config(ledger_id, |config| {
+        config.fee = fee;
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn set_explorer_url_template(
+    ledger_id: Principal,
+    template: Option<String>
+) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    validate_explorer_url_template(&template)?;
+
+    update_ledger_config(ledger_id, |config| {
+        config.explorer_url_template = template;
+    });
+    Ok(())
+}
+
+fn validate_explorer_url_template(template: &Option<String>) -> Result<(), String> {
+    if template.as_ref().is_some_and(|template| !template.contains("{index}")) {
+        return Err("template: must contain the {index} placeholder".to_string());
+    }
+    Ok(())
+}
+
+pub fn explorer_url(ledger_id: Principal, block_index: &BlockIndex) -> Option<String> {
+    let template = ledger_config(ledger_id).explorer_url_template?;
+    Some(
+        template
+            .replace("{ledger}", &ledger_id.to_text())
+            .replace("{index}", &block_index.0.to_string())
+    )
+}
+
+// A stale configured fee would make every leg of a batch fail with BadFee, so batches
+// compare it against the ledger once up front. Returns the fee each leg will pay.
+async fn check_configured_fee(ledger_id: Principal) -> Result<NumTokens, String> {
+    let ledger_fee = get_ledger_fee(ledger_id).await?;
+    if let Some(configured_fee) = ledger_config(ledger_id).fee {
+        if ledger_fee != configured_fee {
+            return Err(format!("configured fee {} != ledger fee {}", configured_fee, ledger_fee));
+        }
+    }
+    Ok(ledger_fee)
+}
+
+#[update]
+pub async fn set_lifetime_outflow_cap(ledger_id: Principal, cap: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    update_ledger_config(ledger_id, |config| {
+        config.lifetime_outflow_cap = cap;
+    });
+    Ok(())
+}
+
+pub fn lifetime_outflow(ledger_id: Principal) -> u64 {
+    LIFETIME_OUTFLOW.with(|outflow| outflow.borrow().get(&ledger_id).unwrap_or_default())
+}
+
+// Only transfers the ledger executed count; a Duplicate answer moved no new funds.
+fn record_lifetime_outflow(ledger_id: Principal, amount: &NumTokens) {
+    let amount = u64::try_from(amount.0.clone()).unwrap_or(u64::MAX);
+    LIFETIME_OUTFLOW.with(|outflow| {
+        let mut outflow = outflow.borrow_mut();
+        let total = outflow.get(&ledger_id).unwrap_or_default().saturating_add(amount);
+        outflow.insert(ledger_id, total);
+    });
+}
+
+fn check_lifetime_cap(ledger_id: Principal, amount: u64) -> Result<(), String> {
+    let Some(cap) = ledger_config(ledger_id).lifetime_outflow_cap else {
+        return Ok(());
+    };
+    let spent = lifetime_outflow(ledger_id);
+    if spent.saturating_add(amount) > cap {
+        return Err(
+            format!(
+                "lifetime cap reached: {} of {} tokens already transferred, {} tokens requested",
+                spent,
+                cap,
+                amount
+            )
+        );
+    }
+    Ok(())
+}
+
+pub fn ledger_config(ledger_id: Principal) -> LedgerConfig {
+    LEDGER_CONFIGS.with(|configs| configs.borrow().get(&ledger_id).unwrap_or_default())
+}
+
+fn update_ledger_config(ledger_id: Principal, f: impl FnOnce(&mut LedgerConfig)) {
+    LEDGER_CONFIGS.with(|configs| {
+        let mut configs = configs.borrow_mut();
+        let mut config = configs.get(&ledger_id).unwrap_or_default();
+        f(&mut config);
+        configs.insert(ledger_id, config);
+    });
+}
+
+fn reserve_amount(ledger_id: Principal) -> u64 {
+    ledger_config(ledger_id).reserve_amount
+}
+
+// The fee send_tokens will pay per transfer: the configured fee if there is one, else
+// the ledger's.
+async fn transfer_fee(ledger_id: Principal) -> Result<NumTokens, String> {
+    match ledger_config(ledger_id).fee {
+        Some(fee) => Ok(NumTokens::from(fee)),
+        None => get_ledger_fee(ledger_id).await,
+    }
+}
+
+async fn get_operational_tokens_balance(ledger_id: Principal) -> Result<NumTokens, String> {
+    let balance = get_tokens_balance(ledger_id).await?;
+    let reserve = NumTokens::from(reserve_amount(ledger_id));
+    if balance > reserve {
+        Ok(balance - reserve)
+    } else {
+        Ok(NumTokens::from(0u64))
+    }
+}
+
+#[update]
+pub async fn set_verify_recipient_canisters(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.verify_recipient_canisters = enabled;
+    });
+    Ok(())
+}
+
+fn validate_safe_recipient_canister(canister_id: Principal) -> Result<(), String> {
+    if !is_canister_principal(canister_id) {
+        return Err(format!("{} is not a canister principal", canister_id));
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn add_safe_recipient_canister(canister_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_safe_recipient_canister(canister_id)?;
+
+    SAFE_RECIPIENT_CANISTERS.with(|canisters| {
+        canisters.borrow_mut().insert(canister_id, ());
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn remove_safe_recipient_canister(canister_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    SAFE_RECIPIENT_CANISTERS.with(|canisters| canisters.borrow_mut().remove(&canister_id)).ok_or(
+        format!("Canister {} is not on the safe recipient list", canister_id)
+    )
+}
+
+// Moves which account every transfer is sent from and every balance check reads,
+// on all ledgers at once. Funds are not migrated: tokens left in the previous main
+// account (the default None subaccount before this is first set) stop counting
+// towards the operational balance and can only be spent by switching back, so move
+// them to the new subaccount before pinning it.
+#[update]
+pub async fn set_main_subaccount(main_subaccount: Option<Subaccount>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.main_subaccount = main_subaccount;
+    });
+    Ok(())
+}
+
+fn validate_observer(observer: Principal) -> Result<(), String> {
+    if observer == Principal::anonymous() {
+        return Err("observer: anonymous principal is not allowed".to_string());
+    }
+    Ok(())
+}
+
+// Observers only ever gain read access: every update that moves funds or changes
+// settings already requires a controller.
+#[update]
+pub async fn add_observer(observer: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_observer(observer)?;
+
+    OBSERVERS.with(|observers| {
+        observers.borrow_mut().insert(observer, ());
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn remove_observer(observer: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    OBSERVERS.with(|observers| observers.borrow_mut().remove(&observer)).ok_or(
+        format!("{} is not an observer", observer)
+    )
+}
+
+#[update]
+pub async fn set_private_history(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.private_history = Some(enabled);
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn set_restrict_to_permitted_ledgers(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.restrict_to_permitted_ledgers = Some(enabled);
+    });
+    Ok(())
+}
+
+fn validate_permitted_ledger(ledger_id: Principal) -> Result<(), String> {
+    if !is_canister_principal(ledger_id) {
+        return Err(format!("{} is not a canister principal", ledger_id));
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn add_permitted_ledger(ledger_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_permitted_ledger(ledger_id)?;
+
+    PERMITTED_LEDGERS.with(|ledgers| {
+        ledgers.borrow_mut().insert(ledger_id, ());
+    });
+    Ok(())
+}
+
+#[update]
+pub async fn remove_permitted_ledger(ledger_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    PERMITTED_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&ledger_id)).ok_or(
+        format!("Ledger {} is not on the permitted ledger list", ledger_id)
+    )
+}
+
+fn validate_budget(budget: &Budget) -> Result<(), String> {
+    if budget.category.is_empty() {
+        return Err("category: must not be empty".to_string());
+    }
+    if budget.period_ns == 0 {
+        return Err("period_ns: must be greater than 0".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_budget(budget: Budget) -> Result<BudgetId, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_budget(&budget)?;
+    validate_ledger_id(budget.ledger_id)?;
+
+    let id = BUDGETS.with(|budgets| {
+        let mut budgets = budgets.borrow_mut();
+        let id = budgets
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or_default();
+        budgets.insert(id, budget);
+        id
+    });
+    Ok(id)
+}
+
+#[update]
+pub async fn remove_budget(id: BudgetId) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    BUDGETS.with(|budgets| budgets.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or(format!("Budget {} not found", id))
+}
+
+pub fn budget_period_start(budget: &Budget) -> u64 {
+    let now = time();
+    now - (now % budget.period_ns)
+}
+
+// History ids grow with time, so the scan walks backwards and stops at the first
+// entry older than since_ns. Legacy entries without a timestamp predate budgets.
+pub fn category_spent(category: &str, ledger_id: Principal, since_ns: u64) -> u64 {
+    TRANSFER_HISTORY.with(|history| {
+        let mut spent = 0u64;
+        for (_, entry) in history.borrow().iter().rev() {
+            let Some(timestamp) = entry.timestamp else {
+                break;
+            };
+            if timestamp < since_ns {
+                break;
+            }
+            if entry.transfer.ledger_id() != ledger_id || entry.transfer.category() != Some(category) {
+                continue;
+            }
+            spent = entry
+                .paid_legs()
+                .iter()
+                .fold(spent, |total, leg| total.saturating_add(leg.amount));
+        }
+        spent
+    })
+}
+
+fn check_budgets(category: Option<&str>, ledger_id: Principal, amount: u64) -> Result<(), String> {
+    let Some(category) = category else {
+        return Ok(());
+    };
+
+    let budgets = BUDGETS.with(|budgets| {
+        budgets
+            .borrow()
+            .iter()
+            .filter(|(_, budget)| {
+                budget.block_when_exhausted && budget.category == category && budget.ledger_id == ledger_id
+            })
+            .collect::<Vec<(BudgetId, Budget)>>()
+    });
+    for (id, budget) in budgets {
+        let spent = category_spent(category, ledger_id, budget_period_start(&budget));
+        if spent.saturating_add(amount) > budget.amount {
+            return Err(
+                format!(
+                    "Budget {} for category {} would be exceeded: {} of {} tokens spent this period, {} tokens requested",
+                    id,
+                    category,
+                    spent,
+                    budget.amount,
+                    amount
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+// With private_history enabled only controllers and observers may read treasury state.
+pub fn check_read_access() -> Result<(), String> {
+    if !treasury_config().private_history.unwrap_or_default() {
+        return Ok(());
+    }
+    check_controller_or_observer()
+}
+
+pub fn check_controller_or_observer() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let is_observer = OBSERVERS.with(|observers| observers.borrow().contains_key(&caller));
+    if !ic_cdk::api::is_controller(&caller) && !is_observer {
+        return Err("Caller is not a controller or observer".to_string());
+    }
+    Ok(())
+}
+
+pub fn treasury_config() -> TreasuryConfig {
+    TREASURY_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+fn update_treasury_config(f: impl FnOnce(&mut TreasuryConfig)) {
+    TREASURY_CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        f(&mut updated);
+        config.set(updated).expect("failed to update treasury config");
+    });
+}
+
+const OPAQUE_PRINCIPAL_CLASS: u8 = 0x01;
+
+fn is_canister_principal(principal: Principal) -> bool {
+    principal.as_slice().last() == Some(&OPAQUE_PRINCIPAL_CLASS)
+}
+
+// When enabled, canister recipients must either be on the safe list or answer a
+// ledger (icrc1_supported_standards) or cycles wallet (wallet_balance) probe.
+// Plain user principals are never probed.
+async fn verify_recipients(recipients: &[Principal]) -> Result<(), String> {
+    if !treasury_config().verify_recipient_canisters {
+        return Ok(());
+    }
+
+    for recipient in recipients {
+        if !is_canister_principal(*recipient) {
+            continue;
+        }
+
+        if SAFE_RECIPIENT_CANISTERS.with(|canisters| canisters.borrow().contains_key(recipient)) {
+            continue;
+        }
+
+        let is_ledger = ic_cdk
+            ::call::<(), (Reserved,)>(*recipient, "icrc1_supported_standards", ()).await
+            .is_ok();
+        if is_ledger {
+            continue;
+        }
+
+        let is_wallet = ic_cdk::call::<(), (Reserved,)>(*recipient, "wallet_balance", ()).await.is_ok();
+        if !is_wallet {
+            return Err(
+                format!("Recipient canister {} does not implement a ledger or wallet interface", recipient)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_on_duplicate(on_duplicate: OnDuplicate) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.on_duplicate = Some(on_duplicate);
+    });
+    Ok(())
+}
+
+// Legs are reordered before execution, so the order recorded in history, leg results and
+// stored resumable batches is the executed one. The sort is stable: equal amounts keep
+// their input order.
+fn order_batch(arg: &mut TransferToMultiple) {
+    match treasury_config().batch_order.unwrap_or_default() {
+        BatchOrder::InputOrder => {}
+        BatchOrder::LargestFirst => arg.principals.sort_by_key(|p| std::cmp::Reverse(p.amount)),
+        BatchOrder::SmallestFirst => arg.principals.sort_by_key(|p| p.amount),
+    }
+}
+
+#[update]
+pub async fn set_batch_order(order: Option<BatchOrder>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.batch_order = order;
+    });
+    Ok(())
+}
+
+fn validate_batch_size_limit(limit: Option<BatchSizeLimit>) -> Result<(), String> {
+    if let Some(BatchSizeLimit::Fixed(0)) = limit {
+        return Err("Fixed batch size limit must be greater than 0".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_batch_size_limit(limit: Option<BatchSizeLimit>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_batch_size_limit(limit)?;
+
+    update_treasury_config(|config| {
+        config.batch_size_limit = limit;
+    });
+    Ok(())
+}
+
+// Instructions a single batch message may spend on ledger transfers.
+fn batch_instruction_budget() -> u64 {
+    (MESSAGE_INSTRUCTION_LIMIT / 100) * BATCH_INSTRUCTION_BUDGET_PERCENT
+}
+
+// Number of legs expected to fit in the batch instruction budget, based on a running
+// average of the instructions measured per ledger transfer since the last upgrade.
+pub fn estimated_safe_batch_size() -> u64 {
+    let per_leg = LEG_INSTRUCTION_ESTIMATE.with(|estimate| estimate.get()).max(1);
+    (batch_instruction_budget() / per_leg).max(1)
+}
+
+fn max_batch_size() -> Option<u64> {
+    match treasury_config().batch_size_limit? {
+        BatchSizeLimit::Fixed(size) => Some(size),
+        BatchSizeLimit::Estimated => Some(estimated_safe_batch_size()),
+    }
+}
+
+fn record_leg_instructions(instructions: u64) {
+    LEG_INSTRUCTION_ESTIMATE.with(|estimate| {
+        // Exponential moving average weighted 1/4 towards the latest sample.
+        estimate.set((estimate.get() * 3 + instructions) / 4);
+    });
+}
+
+struct TokenTransfer {
+    block_index: BlockIndex,
+    duplicate: Option<DuplicateResolution>,
+    fee_correction: Option<FeeCorrection>,
+}
+
+#[derive(Clone)]
+struct FeeCorrection {
+    original_fee: NumTokens,
+    actual_fee: NumTokens,
+}
+
+fn check_not_paused() -> Result<(), String> {
+    if treasury_config().paused.unwrap_or_default() {
+        return Err("Treasury is paused".to_string());
+    }
+    Ok(())
+}
+
+async fn transfer_tokens(arg: TransferArg, ledger_id: Principal) -> Result<TokenTransfer, String> {
+    check_not_paused()?;
+    check_lifetime_cap(ledger_id, u64::try_from(arg.amount.0.clone()).unwrap_or(u64::MAX))?;
+    send_tokens(arg, ledger_id).await
+}
+
+#[derive(Debug, PartialEq)]
+enum NextAttempt {
+    Resend,
+    ResendWithFee(NumTokens),
+    Finish,
+}
+
+// What send_tokens does after the ledger answered attempt number attempts.
+// TemporarilyUnavailable is resent until max_attempts calls have been made. BadFee
+// means nothing was transferred; only a fee we set ourselves can be wrong, so it is
+// corrected when can_correct_fee, at most once per transfer.
+fn next_attempt(
+    result: &Result<BlockIndex, TransferError>,
+    attempts: u32,
+    max_attempts: u32,
+    can_correct_fee: bool
+) -> NextAttempt {
+    match result {
+        Err(TransferError::TemporarilyUnavailable) if attempts < max_attempts => NextAttempt::Resend,
+        Err(TransferError::BadFee { expected_fee }) if can_correct_fee =>
+            NextAttempt::ResendWithFee(expected_fee.clone()),
+        _ => NextAttempt::Finish,
+    }
+}
+
+// Error for a final TransferError other than Duplicate, which is not a rejection.
+fn rejection_message(error: TransferError, ledger_id: Principal, attempts: u32) -> String {
+    match error {
+        TransferError::TemporarilyUnavailable =>
+            format!(
+                "TemporarilyUnavailable: ledger {} was unavailable for {} attempts; the transfer was not executed and is safe to retry later",
+                ledger_id,
+                attempts
+            ),
+        error => format!("ledger transfer error {:?}", error),
+    }
+}
+
+// transfer_tokens without the pause and lifetime cap checks, for emergency_drain only.
+// Drained funds still count towards the lifetime outflow.
+async fn send_tokens(mut arg: TransferArg, ledger_id: Principal) -> Result<TokenTransfer, String> {
+    check_ledger_permitted(ledger_id)?;
+    let receiving_principal = arg.to.owner;
+    let instructions_before = ic_cdk::api::call_context_instruction_counter();
+    let config = ledger_config(ledger_id);
+    let method = config.transfer_method.unwrap_or("icrc1_transfer".to_string());
+    if arg.fee.is_none() {
+        arg.fee = config.fee.map(NumTokens::from);
+    }
+    let max_attempts = treasury_config()
+        .temporarily_unavailable_retries
+        .unwrap_or(DEFAULT_TEMPORARILY_UNAVAILABLE_RETRIES) + 1;
+    let auto_correct_fee = treasury_config().auto_correct_fee.unwrap_or_default();
+    let mut fee_correction = None;
+
+    // TemporarilyUnavailable means the ledger did not process the transfer, so it is
+    // resent with the same created_at_time; should an earlier attempt have landed after
+    // all, the ledger answers Duplicate instead of transferring twice. There is no way
+    // to sleep inside a call, so the only backoff is the round trip of each attempt.
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let started_at = time();
+        let result = ic_cdk::call::<(TransferArg,), (Result<BlockIndex, TransferError>,)>(
+            ledger_id,
+            &method,
+            (arg.clone(),)
+        ).await;
+        record_ledger_latency(ledger_id, started_at);
+        let result = match result {
+            Ok((result,)) => result,
+            Err(e) => {
+                let error = format!("failed to call ledger: {:?}", e);
+                update_ledger_stats(ledger_id, |stats| stats.call_failures += 1);
+                record_error(ErrorKind::CallFailure, ledger_id, &arg, error.clone());
+                return Err(error);
+            }
+        };
+        let can_correct_fee = auto_correct_fee && fee_correction.is_none() && arg.fee.is_some();
+        match next_attempt(&result, attempts, max_attempts, can_correct_fee) {
+            NextAttempt::Resend => {
+                continue;
+            }
+            NextAttempt::ResendWithFee(expected_fee) => {
+                fee_correction = Some(FeeCorrection {
+                    original_fee: arg.fee.replace(expected_fee.clone()).unwrap_or_default(),
+                    actual_fee: expected_fee,
+                });
+                continue;
+            }
+            NextAttempt::Finish => {
+                break result;
+            }
+        }
+    };
+    record_leg_instructions(
+        ic_cdk::api::call_context_instruction_counter().saturating_sub(instructions_before)
+    );
+    let transfer = match result {
+        Ok(block_index) => {
+            record_lifetime_outflow(ledger_id, &arg.amount);
+            Ok(TokenTransfer { block_index, duplicate: None, fee_correction })
+        }
+        Err(TransferError::Duplicate { duplicate_of }) =>
+            Ok(TokenTransfer {
+                block_index: duplicate_of.clone(),
+                duplicate: Some(DuplicateResolution {
+                    receiving_principal,
+                    duplicate_of,
+                    handling: treasury_config().on_duplicate.unwrap_or_default(),
+                }),
+                fee_correction,
+            }),
+        Err(error) => Err(rejection_message(error, ledger_id, attempts)),
+    };
+
+    // Duplicate is the ledger confirming an earlier transfer and resolves to Ok above,
+    // so it counts as a success rather than a rejection.
+    match &transfer {
+        Ok(transfer) =>
+            update_ledger_stats(ledger_id, |stats| {
+                stats.transfers_succeeded += 1;
+                if transfer.fee_correction.is_some() {
+                    stats.fee_corrections += 1;
+                }
+            }),
+        Err(error) => {
+            update_ledger_stats(ledger_id, |stats| stats.ledger_rejections += 1);
+            record_error(ErrorKind::LedgerRejection, ledger_id, &arg, error.clone());
+        }
+    }
+    transfer
+}
+
+#[update]
+pub async fn set_record_ledger_symbol(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.record_ledger_symbol = Some(enabled);
+    });
+    Ok(())
+}
+
+// Opt-in since it costs an icrc1_symbol call per transfer whenever the cached symbol
+// is older than LEDGER_SYMBOL_CACHE_TTL_NS. A failed lookup just leaves the entry
+// without a symbol.
+async fn ledger_symbol_snapshot(ledger_id: Principal) -> Option<String> {
+    if !treasury_config().record_ledger_symbol.unwrap_or_default() {
+        return None;
+    }
+    check_ledger_permitted(ledger_id).ok()?;
+
+    let now = time();
+    let cached = LEDGER_SYMBOL_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .get(&ledger_id)
+            .filter(|(_, fetched_at)| now.saturating_sub(*fetched_at) <= LEDGER_SYMBOL_CACHE_TTL_NS)
+            .map(|(symbol, _)| symbol.clone())
+    });
+    if cached.is_some() {
+        return cached;
+    }
+
+    let started_at = time();
+    let result = ic_cdk::call::<(), (String,)>(ledger_id, "icrc1_symbol", ()).await;
+    record_ledger_latency(ledger_id, started_at);
+    let (symbol,) = result.ok()?;
+    LEDGER_SYMBOL_CACHE.with(|cache| {
+        cache.borrow_mut().insert(ledger_id, (symbol.clone(), now));
+    });
+    Some(symbol)
+}
+
+// With a configured fee that has gone stale, transfers rejected with BadFee are resent
+// once with the fee the ledger expects. Corrections are recorded in history and counted
+// in get_ledger_stats so the configured fee can be fixed.
+#[update]
+pub async fn set_auto_correct_fee(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.auto_correct_fee = Some(enabled);
+    });
+    Ok(())
+}
+
+fn validate_temporarily_unavailable_retries(retries: u32) -> Result<(), String> {
+    if retries > MAX_TEMPORARILY_UNAVAILABLE_RETRIES {
+        return Err(format!("At most {} retries are allowed", MAX_TEMPORARILY_UNAVAILABLE_RETRIES));
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_temporarily_unavailable_retries(retries: u32) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_temporarily_unavailable_retries(retries)?;
+
+    update_treasury_config(|config| {
+        config.temporarily_unavailable_retries = Some(retries);
+    });
+    Ok(())
+}
+
+// Sends the operational balance minus the retained amount and the transfer fee to
+// the destination. With maintain_fee_buffer the retained amount is
+// max(keep_amount, SWEEP_FEE_BUFFER_FEES * fee), so a sweep with keep_amount = 0
+// still leaves enough to pay for future transfers; a larger keep_amount wins.
+#[update]
+pub async fn sweep(arg: SweepArg) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_ledger_id(arg.ledger_id)?;
+    validate_recipient("destination", arg.destination)?;
+
+    let balance = get_operational_tokens_balance(arg.ledger_id).await?;
+    let fee = get_ledger_fee(arg.ledger_id).await?;
+    let mut retained = NumTokens::from(arg.keep_amount);
+    if arg.maintain_fee_buffer {
+        retained = retained.max(fee.clone() * SWEEP_FEE_BUFFER_FEES);
+    }
+
+    let required = retained.clone() + fee;
+    if balance <= required {
+        return Err(
+            format!(
+                "Nothing to sweep: {} tokens available, {} tokens retained including fee",
+                balance,
+                required
+            )
+        );
+    }
+    let amount = u64::try_from((balance - required).0).map_err(|_|
+        "Sweep amount exceeds the supported transfer amount".to_string()
+    )?;
+
+    let transfer = TransferToPrincipal {
+        receiving_principal: arg.destination,
+        amount,
+        ledger_id: arg.ledger_id,
+        category: None,
+    };
+    check_quarantine(&TransferRequest::TransferToPrincipal(transfer.clone()), caller)?;
+    execute_transfer_to_principal(transfer, caller, None).await
+}
+
+// Adds amount to what recipient may pull from ledger_id with claim.
+#[update]
+pub async fn authorize_claim(
+    recipient: Principal,
+    amount: u64,
+    ledger_id: Principal
+) -> Result<Claim, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    if amount == 0 {
+        return Err("Claim amount must be greater than 0".to_string());
+    }
+    validate_recipient("recipient", recipient)?;
+    validate_ledger_id(ledger_id)?;
+
+    CLAIMS.with(|claims| {
+        let mut claims = claims.borrow_mut();
+        let mut claim = claims.get(&(recipient, ledger_id)).unwrap_or_default();
+        claim.authorized = claim.authorized
+            .checked_add(amount)
+            .ok_or("Authorized claim amount overflows".to_string())?;
+        claims.insert((recipient, ledger_id), claim.clone());
+        Ok(claim)
+    })
+}
+
+// Pays out everything the caller is currently authorized to claim on ledger_id.
+#[update]
+pub async fn claim(ledger_id: Principal) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    let claim = CLAIMS.with(|claims| claims.borrow().get(&(caller, ledger_id))).ok_or(
+        "Nothing to claim".to_string()
+    )?;
+    let amount = claim.authorized - claim.claimed;
+    if amount == 0 {
+        return Err("Nothing to claim".to_string());
+    }
+
+    // The ledger guard taken by execute_transfer_to_principal rejects a second claim
+    // while this one is in flight, so the claimable amount cannot be paid twice.
+    let arg = TransferToPrincipal {
+        receiving_principal: caller,
+        amount,
+        ledger_id,
+        category: None,
+    };
+    // A quarantined claim counts as claimed, so claiming again cannot queue the same
+    // amount twice; releasing or rejecting the quarantined transfer settles it.
+    if let Err(error) = check_quarantine(&TransferRequest::TransferToPrincipal(arg.clone()), caller) {
+        mark_claimed(caller, ledger_id, amount);
+        return Err(error);
+    }
+    let block_index = execute_transfer_to_principal(arg, caller, None).await?;
+    mark_claimed(caller, ledger_id, amount);
+    Ok(block_index)
+}
+
+fn mark_claimed(recipient: Principal, ledger_id: Principal, amount: u64) {
+    CLAIMS.with(|claims| {
+        let mut claims = claims.borrow_mut();
+        let mut claim = claims.get(&(recipient, ledger_id)).unwrap_or_default();
+        claim.claimed = claim.claimed.saturating_add(amount);
+        claims.insert((recipient, ledger_id), claim);
+    });
+}
+
+#[update]
+pub async fn export_config_snapshot() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let snapshot = ConfigSnapshot {
+        version: CONFIG_SNAPSHOT_VERSION,
+        treasury_config: treasury_config(),
+        ledger_configs: LEDGER_CONFIGS.with(|configs| configs.borrow().iter().collect()),
+        safe_recipient_canisters: SAFE_RECIPIENT_CANISTERS.with(|canisters| {
+            canisters
+                .borrow()
+                .iter()
+                .map(|(canister_id, _)| canister_id)
+                .collect()
+        }),
+        permitted_ledgers: PERMITTED_LEDGERS.with(|ledgers| {
+            ledgers
+                .borrow()
+                .iter()
+                .map(|(ledger_id, _)| ledger_id)
+                .collect()
+        }),
+        observers: OBSERVERS.with(|observers| {
+            observers
+                .borrow()
+                .iter()
+                .map(|(observer, _)| observer)
+                .collect()
+        }),
+        budgets: BUDGETS.with(|budgets| budgets.borrow().iter().collect()),
+        recurring_transfers: RECURRING_TRANSFERS.with(|recurring_transfers| {
+            recurring_transfers.borrow().iter().collect()
+        }),
+    };
+    serde_json::to_string(&snapshot).map_err(|e| format!("failed to serialize snapshot: {}", e))
+}
+
+// Replaces every setting covered by the snapshot. The snapshot is parsed and checked
+// before anything is touched, and the replacement itself makes no calls, so either
+// all of it is applied or none.
+#[update]
+pub async fn import_config_snapshot(json: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    let snapshot: ConfigSnapshot = serde_json
+        ::from_str(&json)
+        .map_err(|e| format!("invalid snapshot: {}", e))?;
+    if snapshot.version != CONFIG_SNAPSHOT_VERSION {
+        return Err(
+            format!(
+                "Unsupported snapshot version {}, expected {}",
+                snapshot.version,
+                CONFIG_SNAPSHOT_VERSION
+            )
+        );
+    }
+
+    validate_config_snapshot(&snapshot)?;
+
+    TREASURY_CONFIG.with(|config| {
+        config.borrow_mut().set(snapshot.treasury_config).expect("failed to update treasury config");
+    });
+    LEDGER_CONFIGS.with(|configs| {
+        let mut configs = configs.borrow_mut();
+        configs.clear_new();
+        for (ledger_id, config) in snapshot.ledger_configs {
+            configs.insert(ledger_id, config);
+        }
+    });
+    SAFE_RECIPIENT_CANISTERS.with(|canisters| {
+        let mut canisters = canisters.borrow_mut();
+        canisters.clear_new();
+        for canister_id in snapshot.safe_recipient_canisters {
+            canisters.insert(canister_id, ());
+        }
+    });
+    PERMITTED_LEDGERS.with(|ledgers| {
+        let mut ledgers = ledgers.borrow_mut();
+        ledgers.clear_new();
+        for ledger_id in snapshot.permitted_ledgers {
+            ledgers.insert(ledger_id, ());
+        }
+    });
+    OBSERVERS.with(|observers| {
+        let mut observers = observers.borrow_mut();
+        observers.clear_new();
+        for observer in snapshot.observers {
+            observers.insert(observer, ());
+        }
+    });
+    BUDGETS.with(|budgets| {
+        let mut budgets = budgets.borrow_mut();
+        budgets.clear_new();
+        for (id, budget) in snapshot.budgets {
+            budgets.insert(id, budget);
+        }
+    });
+
+    RECURRING_TIMERS.with(|timers| {
+        for (_, timer_id) in timers.borrow_mut().drain() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+    });
+    RECURRING_TRANSFERS.with(|recurring_transfers| {
+        let mut recurring_transfers = recurring_transfers.borrow_mut();
+        recurring_transfers.clear_new();
+        for (id, mut recurring) in snapshot.recurring_transfers {
+            recurring.active = false;
+            recurring.needs_attention = Some(true);
+            recurring_transfers.insert(id, recurring);
+        }
+    });
+    Ok(())
+}
+
+// Runs the checks of every setter over the snapshot, so an import either applies in
+// full or not at all. Errors name the offending entry.
+fn validate_config_snapshot(snapshot: &ConfigSnapshot) -> Result<(), String> {
+    let config = &snapshot.treasury_config;
+    // Ledgers are checked against the snapshot's own whitelist, not the one being replaced.
+    let check_ledger = |ledger_id: Principal| {
+        if ledger_id == Principal::anonymous() || ledger_id == Principal::management_canister() {
+            return Err("ledger_id: invalid ledger ID".to_string());
+        }
+        if
+            config.restrict_to_permitted_ledgers.unwrap_or_default() &&
+            !snapshot.permitted_ledgers.contains(&ledger_id)
+        {
+            return Err(format!("Ledger {} is not on the permitted ledger list", ledger_id));
+        }
+        Ok(())
+    };
+    let in_config = |error: String| format!("treasury_config: {}", error);
+    validate_quarantine_rule(&config.quarantine_rule).map_err(in_config)?;
+    validate_batch_size_limit(config.batch_size_limit).map_err(in_config)?;
+    validate_price_oracle(config.price_oracle).map_err(in_config)?;
+    if let Some(retries) = config.temporarily_unavailable_retries {
+        validate_temporarily_unavailable_retries(retries).map_err(in_config)?;
+    }
+    validate_recurring_shortfall_limit(config.recurring_shortfall_limit).map_err(in_config)?;
+    validate_signer_quorum(&config.signer_quorum).map_err(in_config)?;
+    validate_history_archive(&config.history_archive).map_err(in_config)?;
+
+    for (ledger_id, ledger_config) in &snapshot.ledger_configs {
+        let in_ledger = |error: String| format!("ledger_configs[{}]: {}", ledger_id, error);
+        check_ledger(*ledger_id).map_err(in_ledger)?;
+        validate_ledger_methods(
+            &ledger_config.balance_method,
+            &ledger_config.transfer_method
+        ).map_err(in_ledger)?;
+        validate_explorer_url_template(&ledger_config.explorer_url_template).map_err(in_ledger)?;
+    }
+    for canister_id in &snapshot.safe_recipient_canisters {
+        validate_safe_recipient_canister(*canister_id).map_err(|error| {
+            format!("safe_recipient_canisters: {}", error)
+        })?;
+    }
+    for observer in &snapshot.observers {
+        validate_observer(*observer).map_err(|error| format!("observers: {}", error))?;
+    }
+    for ledger_id in &snapshot.permitted_ledgers {
+        validate_permitted_ledger(*ledger_id).map_err(|error| format!("permitted_ledgers: {}", error))?;
+    }
+    for (id, budget) in &snapshot.budgets {
+        validate_budget(budget)
+            .and_then(|_| check_ledger(budget.ledger_id))
+            .map_err(|error| format!("budgets[{}]: {}", id, error))?;
+    }
+    for (id, recurring) in &snapshot.recurring_transfers {
+        validate_recurring_transfer(&recurring.transfer, recurring.interval_ns).map_err(|error| {
+            format!("recurring_transfers[{}]: {}", id, error)
+        })?;
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_paused(paused: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    update_treasury_config(|config| {
+        config.paused = Some(paused);
+    });
+    Ok(())
+}
+
+// Break-glass recovery: only allowed while paused, and moves the entire balance of
+// the main account, reserve included, minus the ledger fee. Without a destination
+// the funds go to the first controller listed in the canister settings. It is the one
+// outgoing path that skips quarantine, since a held drain would defeat its purpose.
+#[update]
+pub async fn emergency_drain(
+    ledger_id: Principal,
+    destination: Option<Principal>
+) -> Result<BlockIndex, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+    if !treasury_config().paused.unwrap_or_default() {
+        return Err("Treasury must be paused before an emergency drain".to_string());
+    }
+
+    validate_ledger_id(ledger_id)?;
+    let destination = match destination {
+        Some(destination) => destination,
+        None =>
+            canister_status(CanisterIdRecord { canister_id: ic_cdk::id() }).await
+                .map_err(|e| format!("failed to read controllers: {:?}", e))?
+                .0.settings.controllers.first()
+                .copied()
+                .ok_or("Canister has no controllers".to_string())?,
+    };
+    validate_recipient("destination", destination)?;
+
+    let _guard = LedgerGuard::acquire(ledger_id)?;
+    let balance = get_tokens_balance(ledger_id).await?;
+    let fee = get_ledger_fee(ledger_id).await?;
+    if balance <= fee {
+        return Err(format!("Nothing to drain: {} tokens available, fee is {}", balance, fee));
+    }
+    let amount = u64::try_from((balance - fee).0).map_err(|_|
+        "Drain amount exceeds the supported transfer amount".to_string()
+    )?;
+
+    let ledger_symbol = ledger_symbol_snapshot(ledger_id).await;
+    let transfer = send_tokens(transfer_arg(destination, amount), ledger_id).await?;
+
+    let arg = TransferToPrincipal {
+        receiving_principal: destination,
+        amount,
+        ledger_id,
+        category: None,
+    };
+    let mut entry = HistoryEntry::new(TransferHistory::EmergencyDrain(arg), caller);
+    entry.block_indices = Some(vec![transfer.block_index.clone()]);
+    entry.duplicates = transfer.duplicate.map(|duplicate| vec![duplicate]);
+    entry.ledger_symbol = ledger_symbol;
+    entry.record_fee_correction(transfer.fee_correction.as_ref());
+    record_history(entry);
+    Ok(transfer.block_index)
+}
+
+// Values every ledger the treasury knows about (configured or seen in history) in
+// units of reference_ledger. Tokens without a price or whose balance could not be
+// fetched are listed in unvalued instead of failing the whole call.
+#[update]
+pub async fn get_portfolio_value(
+    reference_ledger: Principal,
+    price_feed: Vec<TokenPrice>
+) -> Result<PortfolioValue, String> {
+    // Each call costs the treasury a price lookup plus a balance call per ledger.
+    check_controller_or_observer()?;
+    validate_ledger_id(reference_ledger)?;
+
+    let mut ledgers = known_ledgers();
+    ledgers.insert(reference_ledger);
+
+    let missing = ledgers
+        .iter()
+        .filter(|ledger_id| {
+            **ledger_id != reference_ledger &&
+                !price_feed.iter().any(|price| price.ledger_id == **ledger_id)
+        })
+        .cloned()
+        .collect::<Vec<Principal>>();
+    let mut price_feed = price_feed;
+    price_feed.extend(oracle_prices(reference_ledger, missing).await);
+
+    let mut total_value = NumTokens::from(0u64);
+    let mut valued = Vec::new();
+    let mut unvalued = Vec::new();
+    for ledger_id in ledgers {
+        let price = if ledger_id == reference_ledger {
+            Some(PRICE_SCALE)
+        } else {
+            price_feed
+                .iter()
+                .find(|price| price.ledger_id == ledger_id)
+                .map(|price| price.price)
+        };
+        let Some(price) = price else {
+            unvalued.push((ledger_id, "No price available".to_string()));
+            continue;
+        };
+
+        let balance = match get_tokens_balance(ledger_id).await {
+            Ok(balance) => balance,
+            Err(error) => {
+                unvalued.push((ledger_id, error));
+                continue;
+            }
+        };
+        let value = (balance.clone() * price) / PRICE_SCALE;
+        total_value += value.clone();
+        valued.push(TokenValuation {
+            ledger_id,
+            balance,
+            value: u64::try_from(value.0).map_err(|_| "Token value overflows u64".to_string())?,
+        });
+    }
+
+    Ok(PortfolioValue {
+        reference_ledger,
+        total_value: u64::try_from(total_value.0).map_err(|_|
+            "Portfolio value overflows u64".to_string()
+        )?,
+        valued,
+        unvalued,
+    })
+}
+
+fn validate_price_oracle(canister_id: Option<Principal>) -> Result<(), String> {
+    if canister_id.is_some_and(|canister_id| !is_canister_principal(canister_id)) {
+        return Err("canister_id: price oracle must be a canister".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+pub async fn set_price_oracle(
+    canister_id: Option<Principal>,
+    cache_ttl_ns: Option<u64>
+) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(caller).await {
+        return Err("Caller is not a controller".to_string());
+    }
+
+    validate_price_oracle(canister_id)?;
+
+    update_treasury_config(|config| {
+        config.price_oracle = canister_id;
+        config.price_cache_ttl_ns = cache_ttl_ns;
+    });
+    PRICE_CACHE.with(|cache| cache.borrow_mut().clear());
+    Ok(())
+}
+
+// The oracle must implement
+//   get_prices : (reference_ledger : principal, ledgers : vec principal) -> (vec TokenPrice)
+// returning prices in the same PRICE_SCALE convention as TokenPrice. Cached prices
+// are reused for price_cache_ttl_ns; if the oracle is unreachable, stale cached
+// prices are used and anything else is simply left unpriced.
+async fn oracle_prices(reference_ledger: Principal, ledgers: Vec<Principal>) -> Vec<TokenPrice> {
+    let config = treasury_config();
+    let Some(oracle) = config.price_oracle else {
+        return Vec::new();
+    };
+    let ttl = config.price_cache_ttl_ns.unwrap_or(DEFAULT_PRICE_CACHE_TTL_NS);
+    let now = time();
+
+    let cached = |ledger_id: &Principal, fresh_only: bool| {
+        PRICE_CACHE.with(|cache| {
+            cache
+                .borrow()
+                .get(&(reference_ledger, *ledger_id))
+                .filter(|(_, fetched_at)| !fresh_only || now.saturating_sub(*fetched_at) <= ttl)
+                .map(|(price, _)| TokenPrice { ledger_id: *ledger_id, price: *price })
+        })
+    };
+
+    let mut prices = ledgers
+        .iter()
+        .filter_map(|ledger_id| cached(ledger_id, true))
+        .collect::<Vec<TokenPrice>>();
+    let stale = ledgers
+        .into_iter()
+        .filter(|ledger_id| !prices.iter().any(|price| price.ledger_id == *ledger_id))
+        .collect::<Vec<Principal>>();
+    if stale.is_empty() {
+        return prices;
+    }
+
+    let result = ic_cdk::call::<(Principal, Vec<Principal>), (Vec<TokenPrice>,)>(
+        oracle,
+        "get_prices",
+        (reference_ledger, stale.clone())
+    ).await;
+    match result {
+        Ok((fetched,)) => {
+            PRICE_CACHE.with(|cache| {
+                let mut cache = cache.borrow_mut();
+                for price in fetched.iter() {
+                    cache.insert((reference_ledger, price.ledger_id), (price.price, now));
+                }
+            });
+            prices.extend(fetched.into_iter().filter(|price| stale.contains(&price.ledger_id)));
+        }
+        Err(error) => {
+            ic_cdk::println!("Price oracle {} unavailable: {:?}", oracle, error);
+            prices.extend(stale.iter().filter_map(|ledger_id| cached(ledger_id, false)));
+        }
+    }
+    prices
+}
+
+pub fn known_ledgers() -> BTreeSet<Principal> {
+    let mut ledgers = LEDGER_CONFIGS.with(|configs| {
+        configs
+            .borrow()
+            .iter()
+            .map(|(ledger_id, _)| ledger_id)
+            .collect::<BTreeSet<Principal>>()
+    });
+    TRANSFER_HISTORY.with(|history| {
+        for (_, entry) in history.borrow().iter() {
+            ledgers.insert(entry.transfer.ledger_id());
+        }
+    });
+    ledgers
+}
+
+#[update(guard = "check_read_access")]
+pub async fn simulate_transfer_to_principal(arg: TransferToPrincipal) -> Result<TransferSimulation, String> {
+    validate_transfer_to_principal(arg.clone())?;
+    simulate_transfer(arg.ledger_id, arg.amount, 1).await
+}
+
+#[update(guard = "check_read_access")]
+pub async fn simulate_transfer_to_multiple(arg: TransferToMultiple) -> Result<TransferSimulation, String> {
+    validate_transfer_to_multiple(arg.clone())?;
+    let total_amount = batch_total(&arg.principals)?;
+    simulate_transfer(arg.ledger_id, total_amount, arg.principals.len() as u64).await
+}
+
+async fn simulate_transfer(
+    ledger_id: Principal,
+    total_amount: u64,
+    transfer_count: u64
+) -> Result<TransferSimulation, String> {
+    // Transfers can only spend the operational balance, so that is what is simulated.
+    let balance = get_operational_tokens_balance(ledger_id).await?;
+    let fee = transfer_fee(ledger_id).await?;
+    let total_cost = batch_total_cost(total_amount, fee_as_u64(&fee)?, transfer_count)?;
+    let total_fees = NumTokens::from(total_cost - total_amount);
+    let total_cost = NumTokens::from(total_cost);
+
+    let mut warnings = Vec::new();
+    let balance_after = if balance >= total_cost {
+        balance.clone() - total_cost.clone()
+    } else {
+        warnings.push(
+            format!(
+                "Insufficient operational balance: {} tokens available, {} tokens required including fees",
+                balance,
+                total_cost
+            )
+        );
+        NumTokens::from(0u64)
+    };
+    if balance_after <= fee {
+        warnings.push(
+            format!(
+                "Transfer would leave {} tokens, at or below one fee ({}); consider keeping a buffer for future fees",
+                balance_after,
+                fee
+            )
+        );
+    }
+
+    Ok(TransferSimulation {
+        total_amount,
+        fee,
+        total_fees,
+        total_cost,
+        balance,
+        balance_after,
+        warnings,
+    })
+}
+
+async fn get_ledger_fee(ledger_id: Principal) -> Result<NumTokens, String> {
+    check_ledger_permitted(ledger_id)?;
+    let started_at = time();
+    let result = ic_cdk::call::<(), (NumTokens,)>(ledger_id, "icrc1_fee", ()).await;
+    record_ledger_latency(ledger_id, started_at);
+    let fee = result.map_err(|e| format!("failed to call ledger: {:?}", e))?.0;
+    Ok(fee)
+}
+
+async fn get_tokens_balance(ledger_id: Principal) -> Result<NumTokens, String> {
+    check_ledger_permitted(ledger_id)?;
+    let owner = ic_cdk::id();
+    let method = ledger_config(ledger_id).balance_method.unwrap_or("icrc1_balance_of".to_string());
+    let account = Account {
+        owner,
+        subaccount: treasury_config().main_subaccount,
+    };
+    let started_at = time();
+    let result = ic_cdk::call::<(Account,), (NumTokens,)>(ledger_id, &method, (account,)).await;
+    record_ledger_latency(ledger_id, started_at);
+    let user_balance = result.map_err(|e| format!("failed to call ledger: {:?}", e))?.0;
+    Ok(user_balance)
+}
+
+#[update]
+pub async fn get_module_hash() -> Result<Vec<u8>, String> {
+    let canister_id = ic_cdk::id();
+
+    let (status,) = canister_status(CanisterIdRecord { canister_id }).await.map_err(|e|
+        format!("failed to fetch canister status: {:?}", e)
+    )?;
+    status.module_hash.ok_or("No module installed".to_string())
+}
+
+async fn is_controller(principal: Principal) -> bool {
+    let canister_id = ic_cdk::id();
+
+    let result = canister_status(CanisterIdRecord { canister_id }).await;
+
+    match result {
+        Ok(status) => { status.0.settings.controllers.contains(&principal) }
+        Err(error) => {
+            let error_message = format!("{:?}", error);
+            error_message.contains(&principal.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_recipient_rejects_anonymous() {
+        let error = validate_recipient("to", Principal::anonymous()).unwrap_err();
+        assert_eq!(error, "to: cannot transfer to the anonymous principal");
+    }
+
+    #[test]
+    fn validate_recipient_rejects_management_canister() {
+        let error = validate_recipient("to", Principal::management_canister()).unwrap_err();
+        assert_eq!(error, "to: cannot transfer to the management canister");
+    }
+
+    #[test]
+    fn validate_recipient_rejects_reserved_class() {
+        let principal = Principal::from_slice(&[1, 2, 3, RESERVED_PRINCIPAL_CLASS]);
+        let error = validate_recipient("principals[2].receiving_principal", principal).unwrap_err();
+        assert!(error.starts_with("principals[2].receiving_principal: principal"));
+        assert!(error.ends_with("uses the reserved principal class"));
+    }
+
+    #[test]
+    fn validate_recipient_accepts_user_and_canister_principals() {
+        let user = Principal::self_authenticating([7u8; 32]);
+        let canister = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+        assert!(validate_recipient("to", user).is_ok());
+        assert!(validate_recipient("to", canister).is_ok());
+    }
+
+    #[test]
+    fn ledger_guard_rejects_interleaved_acquire_until_dropped() {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+        let other_ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 3, 1, 1]);
+
+        let guard = LedgerGuard::acquire(ledger_id).unwrap();
+        let error = LedgerGuard::acquire(ledger_id).err().unwrap();
+        assert!(error.starts_with("Another transfer on ledger"));
+        let other_guard = LedgerGuard::acquire(other_ledger_id).unwrap();
+
+        drop(guard);
+        let guard = LedgerGuard::acquire(ledger_id).unwrap();
+        drop(guard);
+        drop(other_guard);
+    }
+
+    #[test]
+    fn next_attempt_resends_temporarily_unavailable_until_the_limit() {
+        let unavailable: Result<BlockIndex, TransferError> = Err(TransferError::TemporarilyUnavailable);
+        let max_attempts = DEFAULT_TEMPORARILY_UNAVAILABLE_RETRIES + 1;
+        for attempts in 1..max_attempts {
+            assert_eq!(next_attempt(&unavailable, attempts, max_attempts, false), NextAttempt::Resend);
+        }
+        assert_eq!(next_attempt(&unavailable, max_attempts, max_attempts, false), NextAttempt::Finish);
+        // With retries set to 0 the first answer is final.
+        assert_eq!(next_attempt(&unavailable, 1, 1, false), NextAttempt::Finish);
+    }
+
+    #[test]
+    fn next_attempt_corrects_bad_fee_only_when_allowed() {
+        let bad_fee: Result<BlockIndex, TransferError> = Err(TransferError::BadFee {
+            expected_fee: NumTokens::from(20u64),
+        });
+        assert_eq!(
+            next_attempt(&bad_fee, 1, 3, true),
+            NextAttempt::ResendWithFee(NumTokens::from(20u64))
+        );
+        assert_eq!(next_attempt(&bad_fee, 1, 3, false), NextAttempt::Finish);
+    }
+
+    #[test]
+    fn next_attempt_finishes_on_success_and_other_errors() {
+        let ok: Result<BlockIndex, TransferError> = Ok(BlockIndex::from(5u64));
+        let duplicate: Result<BlockIndex, TransferError> = Err(TransferError::Duplicate {
+            duplicate_of: BlockIndex::from(4u64),
+        });
+        let insufficient: Result<BlockIndex, TransferError> = Err(TransferError::InsufficientFunds {
+            balance: NumTokens::from(1u64),
+        });
+        for result in [ok, duplicate, insufficient] {
+            assert_eq!(next_attempt(&result, 1, 3, true), NextAttempt::Finish);
+        }
+    }
+
+    #[test]
+    fn rejection_message_reports_exhausted_retries() {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+        let message = rejection_message(TransferError::TemporarilyUnavailable, ledger_id, 3);
+        assert!(message.starts_with("TemporarilyUnavailable: ledger"));
+        assert!(message.contains("unavailable for 3 attempts"));
+        let message = rejection_message(TransferError::TooOld, ledger_id, 1);
+        assert_eq!(message, "ledger transfer error TooOld");
+    }
+
+    // Polls a future that never has to wait, which is all the fakes below need.
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match future.as_mut().poll(&mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("future was not ready"),
+        }
+    }
+
+    #[test]
+    fn collect_balances_reports_a_failing_ledger_in_its_own_entry() {
+        let healthy = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+        let failing = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 3, 1, 1]);
+        let ledger_ids = vec![healthy, failing, Principal::anonymous()];
+
+        let balances = block_on(
+            collect_balances(ledger_ids, |ledger_id| async move {
+                if ledger_id == failing {
+                    Err("failed to call ledger: canister rejected".to_string())
+                } else {
+                    Ok(NumTokens::from(100u64))
+                }
+            })
+        );
+
+        assert_eq!(balances.len(), 3);
+        assert_eq!(balances[0], (healthy, Ok(NumTokens::from(100u64))));
+        assert_eq!(balances[1], (failing, Err("failed to call ledger: canister rejected".to_string())));
+        assert_eq!(balances[2], (Principal::anonymous(), Err("ledger_id: invalid ledger ID".to_string())));
+    }
+
+    // The shape of TransferHistory before categories existed.
+    #[derive(CandidType)]
+    struct LegacyTransferToPrincipal {
+        receiving_principal: Principal,
+        amount: u64,
+        ledger_id: Principal,
+    }
+
+    #[derive(CandidType)]
+    enum LegacyTransferHistory {
+        TransferToPrincipal(LegacyTransferToPrincipal),
+    }
+
+    #[test]
+    fn history_entry_decodes_a_bare_legacy_transfer_history() {
+        let recipient = Principal::self_authenticating([7u8; 32]);
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+        let legacy = LegacyTransferHistory::TransferToPrincipal(LegacyTransferToPrincipal {
+            receiving_principal: recipient,
+            amount: 42,
+            ledger_id,
+        });
+
+        let entry = HistoryEntry::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+
+        let TransferHistory::TransferToPrincipal(transfer) = &entry.transfer else {
+            panic!("expected a TransferToPrincipal");
+        };
+        assert_eq!(transfer.receiving_principal, recipient);
+        assert_eq!(transfer.amount, 42);
+        assert_eq!(transfer.ledger_id, ledger_id);
+        assert_eq!(transfer.category, None);
+        assert_eq!(entry.timestamp, None);
+        assert_eq!(entry.caller, None);
+        assert!(entry.block_indices.is_none());
+        assert_eq!(entry.fee_corrected, None);
+    }
+
+    fn snapshot_with_budget(period_ns: u64) -> ConfigSnapshot {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]);
+        ConfigSnapshot {
+            version: CONFIG_SNAPSHOT_VERSION,
+            treasury_config: TreasuryConfig::default(),
+            ledger_configs: vec![],
+            safe_recipient_canisters: vec![],
+            permitted_ledgers: vec![],
+            observers: vec![],
+            budgets: vec![
+                (1, Budget {
+                    category: "grants".to_string(),
+                    ledger_id,
+                    period_ns,
+                    amount: 1_000,
+                    block_when_exhausted: true,
+                })
+            ],
+            recurring_transfers: vec![],
+        }
+    }
+
+    #[test]
+    fn config_snapshot_validation_accepts_valid_budget() {
+        assert!(validate_config_snapshot(&snapshot_with_budget(1_000)).is_ok());
+    }
+
+    #[test]
+    fn config_snapshot_validation_rejects_zero_budget_period() {
+        let error = validate_config_snapshot(&snapshot_with_budget(0)).unwrap_err();
+        assert_eq!(error, "budgets[1]: period_ns: must be greater than 0");
+    }
+
+    #[test]
+    fn config_snapshot_validation_rejects_invalid_config_settings() {
+        let mut snapshot = snapshot_with_budget(1_000);
+        snapshot.treasury_config.batch_size_limit = Some(BatchSizeLimit::Fixed(0));
+        assert!(validate_config_snapshot(&snapshot).is_err());
+
+        let mut snapshot = snapshot_with_budget(1_000);
+        snapshot.treasury_config.quarantine_rule = Some(QuarantineRule::NewRecipientDelay(1));
+        assert!(validate_config_snapshot(&snapshot).is_err());
+    }
+
+    #[test]
+    fn config_snapshot_validation_rejects_anonymous_observer() {
+        let mut snapshot = snapshot_with_budget(1_000);
+        snapshot.observers.push(Principal::anonymous());
+        let error = validate_config_snapshot(&snapshot).unwrap_err();
+        assert_eq!(error, "observers: observer: anonymous principal is not allowed");
+    }
+
+    #[test]
+    fn config_snapshot_validation_rejects_non_canister_safe_recipient() {
+        let mut snapshot = snapshot_with_budget(1_000);
+        let user = Principal::self_authenticating([9u8; 32]);
+        snapshot.safe_recipient_canisters.push(user);
+        let error = validate_config_snapshot(&snapshot).unwrap_err();
+        assert_eq!(error, format!("safe_recipient_canisters: {} is not a canister principal", user));
+    }
+
+    #[test]
+    fn config_snapshot_validation_checks_ledgers_against_snapshot_whitelist() {
+        let mut snapshot = snapshot_with_budget(1_000);
+        snapshot.treasury_config.restrict_to_permitted_ledgers = Some(true);
+        assert!(validate_config_snapshot(&snapshot).is_err());
+
+        snapshot.permitted_ledgers.push(snapshot.budgets[0].1.ledger_id);
+        assert!(validate_config_snapshot(&snapshot).is_ok());
+    }
+
+    fn persist_jobs_for_upgrade() -> (RecurringId, ScheduleId) {
+        let transfer = TransferToPrincipal {
+            receiving_principal: Principal::self_authenticating([7u8; 32]),
+            amount: 100,
+            ledger_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]),
+            category: None,
+        };
+        RECURRING_TRANSFERS.with(|recurring_transfers| {
+            let mut recurring_transfers = recurring_transfers.borrow_mut();
+            recurring_transfers.insert(1, RecurringTransfer {
+                transfer: transfer.clone(),
+                interval_ns: MIN_RECURRING_INTERVAL_NS,
+                next_execution_ns: 10,
+                active: true,
+                consecutive_shortfalls: None,
+                needs_attention: None,
+                events: None,
+                caller: None,
+            });
+            recurring_transfers.insert(2, RecurringTransfer {
+                transfer: transfer.clone(),
+                interval_ns: MIN_RECURRING_INTERVAL_NS,
+                next_execution_ns: 10,
+                active: false,
+                consecutive_shortfalls: None,
+                needs_attention: Some(true),
+                events: None,
+                caller: None,
+            });
+        });
+        SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+            let mut scheduled_transfers = scheduled_transfers.borrow_mut();
+            scheduled_transfers.insert(1, ScheduledTransfer {
+                payload: TransferRequest::TransferToPrincipal(transfer.clone()),
+                execute_at_ns: 20,
+                status: ScheduleStatus::Pending,
+                caller: None,
+                immediate: None,
+            });
+            scheduled_transfers.insert(2, ScheduledTransfer {
+                payload: TransferRequest::TransferToPrincipal(transfer),
+                execute_at_ns: 20,
+                status: ScheduleStatus::Cancelled,
+                caller: None,
+                immediate: None,
+            });
+        });
+        (1, 1)
+    }
+
+    fn mismatches(timers: Vec<TimerInfo>) -> Vec<(u64, String)> {
+        timers
+            .into_iter()
+            .filter_map(|timer| Some((timer.id?, timer.mismatch?)))
+            .collect()
+    }
+
+    #[test]
+    fn job_timers_report_jobs_left_unarmed_after_upgrade() {
+        persist_jobs_for_upgrade();
+
+        // Heap state, and with it every armed timer, is gone after an upgrade.
+        let found = mismatches(job_timers(&BTreeSet::new(), &BTreeSet::new()));
+        assert_eq!(found, vec![
+            (1, "Active recurring transfer has no armed timer".to_string()),
+            (1, "Pending scheduled transfer has no armed timer".to_string())
+        ]);
+    }
+
+    #[test]
+    fn job_timers_match_once_every_live_job_is_rearmed() {
+        let (recurring_id, schedule_id) = persist_jobs_for_upgrade();
+
+        let timers = job_timers(&BTreeSet::from([recurring_id]), &BTreeSet::from([schedule_id]));
+        assert_eq!(timers.len(), 2);
+        assert!(mismatches(timers).is_empty());
+    }
+
+    #[test]
+    fn job_timers_report_timers_armed_for_dead_jobs() {
+        persist_jobs_for_upgrade();
+
+        let found = mismatches(job_timers(&BTreeSet::from([1, 2, 3]), &BTreeSet::from([1, 2])));
+        assert_eq!(found, vec![
+            (2, "Armed for an inactive recurring transfer".to_string()),
+            (3, "Armed for a recurring transfer that no longer exists".to_string()),
+            (2, "Armed for a scheduled transfer that is no longer pending".to_string())
+        ]);
+    }
+
+    fn leg(amount: u64) -> PrincipalTransfer {
+        PrincipalTransfer { receiving_principal: Principal::self_authenticating([8u8; 32]), amount }
+    }
+
+    #[test]
+    fn batch_total_rejects_amounts_that_overflow() {
+        assert_eq!(batch_total(&[leg(u64::MAX - 1), leg(1)]), Ok(u64::MAX));
+        assert_eq!(
+            batch_total(&[leg(u64::MAX), leg(1)]),
+            Err("Total transfer amount overflows".to_string())
+        );
+    }
+
+    #[test]
+    fn batch_funding_rejects_a_batch_whose_amounts_fit_but_fees_do_not() {
+        let total_amount = batch_total(&[leg(60), leg(30)]).unwrap();
+
+        let funding = batch_funding(NumTokens::from(100u64), total_amount, 5, 2).unwrap();
+        assert_eq!(funding.total_cost, NumTokens::from(100u64));
+
+        let error = batch_funding(NumTokens::from(100u64), total_amount, 6, 2).err().unwrap();
+        assert_eq!(
+            error,
+            "Insufficient operational balance: 100 tokens available, 102 tokens required including fees (90 requested)"
+        );
+    }
+
+    #[test]
+    fn batch_total_cost_rejects_fees_that_overflow_a_fitting_total() {
+        let total_amount = batch_total(&[leg(u64::MAX - 15), leg(5)]).unwrap();
+        assert_eq!(batch_total_cost(total_amount, 5, 2), Ok(u64::MAX));
+        assert_eq!(
+            batch_total_cost(total_amount, 6, 2),
+            Err("Total transfer cost including fees overflows".to_string())
+        );
+    }
+
+    fn history_entries(count: u64) -> Vec<(u64, HistoryEntry)> {
+        let transfer = TransferHistory::TransferToMultiple(TransferToMultiple {
+            principals: vec![leg(10), leg(20), leg(30)],
+            ledger_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]),
+            category: None,
+        });
+        (1..=count)
+            .map(|id| (id, HistoryEntry::from_bytes(Cow::Owned(Encode!(&transfer).unwrap()))))
+            .collect()
+    }
+
+    fn batch_ids(batch: Vec<(u64, HistoryEntry)>) -> Vec<u64> {
+        batch
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    #[test]
+    fn archive_batch_stops_before_exceeding_the_byte_limit() {
+        let entries = history_entries(5);
+        let entry_bytes = std::mem::size_of::<u64>() + entries[0].1.to_bytes().len();
+
+        let batch = archive_batch(entries.into_iter(), MAX_ARCHIVE_BATCH, entry_bytes * 2 + entry_bytes / 2);
+        assert_eq!(batch_ids(batch), vec![1, 2]);
+    }
+
+    #[test]
+    fn archive_batch_always_takes_the_first_entry() {
+        let batch = archive_batch(history_entries(3).into_iter(), MAX_ARCHIVE_BATCH, 1);
+        assert_eq!(batch_ids(batch), vec![1]);
+    }
+
+    #[test]
+    fn archive_batch_respects_the_entry_limit() {
+        let batch = archive_batch(history_entries(5).into_iter(), 3, MAX_ARCHIVE_BATCH_BYTES);
+        assert_eq!(batch_ids(batch), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn archiving_guard_clears_the_in_flight_flag_when_dropped() {
+        let guard = ArchivingGuard::acquire().expect("first archiving round");
+        assert!(ArchivingGuard::acquire().is_none());
+        assert!(HISTORY_ARCHIVING_IN_FLIGHT.with(|in_flight| in_flight.get()));
+
+        drop(guard);
+        assert!(!HISTORY_ARCHIVING_IN_FLIGHT.with(|in_flight| in_flight.get()));
+        assert!(ArchivingGuard::acquire().is_some());
+    }
+
+    fn batch_entry_with_duplicate() -> HistoryEntry {
+        let paid = Principal::self_authenticating([10u8; 32]);
+        let deduplicated = Principal::self_authenticating([11u8; 32]);
+        let transfer = TransferHistory::TransferToMultiple(TransferToMultiple {
+            principals: vec![
+                PrincipalTransfer { receiving_principal: paid, amount: 40 },
+                PrincipalTransfer { receiving_principal: deduplicated, amount: 60 }
+            ],
+            ledger_id: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]),
+            category: Some("grants".to_string()),
+        });
+        let mut entry = HistoryEntry::from_bytes(Cow::Owned(Encode!(&transfer).unwrap()));
+        entry.timestamp = Some(1_000);
+        entry.block_indices = Some(vec![BlockIndex::from(12u64), BlockIndex::from(3u64)]);
+        entry.duplicates = Some(vec![DuplicateResolution {
+            receiving_principal: deduplicated,
+            duplicate_of: BlockIndex::from(3u64),
+            handling: OnDuplicate::TreatAsSuccess,
+        }]);
+        entry
+    }
+
+    #[test]
+    fn paid_legs_leave_out_deduplicated_legs() {
+        let entry = batch_entry_with_duplicate();
+        let paid = entry.paid_legs();
+        assert_eq!(paid.len(), 1);
+        assert_eq!(paid[0].amount, 40);
+        assert_eq!(entry.transfer.legs().len(), 2);
+    }
+
+    #[test]
+    fn category_spent_does_not_count_deduplicated_legs() {
+        let entry = batch_entry_with_duplicate();
+        let ledger_id = entry.transfer.ledger_id();
+        TRANSFER_HISTORY.with(|history| history.borrow_mut().insert(1, entry));
+
+        assert_eq!(category_spent("grants", ledger_id, 0), 40);
+    }
+
+    #[test]
+    fn due_jobs_on_a_busy_ledger_stay_pending_instead_of_failing() {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]);
+        let signer = Principal::self_authenticating([12u8; 32]);
+        let single = ScheduledTransfer {
+            payload: TransferRequest::TransferToPrincipal(TransferToPrincipal {
+                receiving_principal: Principal::self_authenticating([13u8; 32]),
+                amount: 100,
+                ledger_id,
+                category: None,
+            }),
+            execute_at_ns: 0,
+            status: ScheduleStatus::Pending,
+            caller: Some(signer),
+            immediate: Some(true),
+        };
+        let batch = ScheduledTransfer {
+            payload: TransferRequest::TransferToMultiple(TransferToMultiple {
+                principals: vec![leg(10), leg(20)],
+                ledger_id,
+                category: None,
+            }),
+            ..single.clone()
+        };
+        SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+            let mut scheduled_transfers = scheduled_transfers.borrow_mut();
+            scheduled_transfers.insert(1, single.clone());
+            scheduled_transfers.insert(2, batch.clone());
+        });
+
+        // A job that came due in the same round is mid-transfer on the ledger.
+        let in_flight = LedgerGuard::acquire(ledger_id).unwrap();
+        assert!(!block_on(run_scheduled_transfer(1, single)));
+        assert!(!block_on(run_scheduled_transfer(2, batch)));
+        drop(in_flight);
+
+        for id in [1, 2] {
+            let status = SCHEDULED_TRANSFERS.with(|scheduled_transfers| {
+                scheduled_transfers.borrow().get(&id).unwrap().status
+            });
+            assert!(matches!(status, ScheduleStatus::Pending));
+        }
+    }
+
+    #[test]
+    fn is_ledger_busy_only_matches_the_guard_error() {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, OPAQUE_PRINCIPAL_CLASS]);
+        let other_ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 3, 1, OPAQUE_PRINCIPAL_CLASS]);
+        let busy: Result<(), String> = Err(ledger_busy_error(ledger_id));
+
+        assert!(is_ledger_busy(&busy, ledger_id));
+        assert!(!is_ledger_busy(&busy, other_ledger_id));
+        assert!(!is_ledger_busy(&Err::<(), String>("Treasury is paused".to_string()), ledger_id));
+        assert!(!is_ledger_busy(&Ok::<(), String>(()), ledger_id));
+    }
+}
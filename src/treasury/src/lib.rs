@@ -1,23 +1,316 @@
 extern crate serde;
-use icrc_ledger_types::icrc1::transfer::BlockIndex;
-use api::updates::{TransferHistory, TransferToPrincipal, TransferToMultiple};
+use icrc_ledger_types::icrc1::{ account::Subaccount, transfer::{ BlockIndex, NumTokens } };
+use api::updates::{
+    ArchiveConfig,
+    ArchivedRange,
+    BatchOrder,
+    BatchProgress,
+    BatchRun,
+    BatchRunId,
+    BatchSizeLimit,
+    Budget,
+    BudgetId,
+    Claim,
+    ConditionalOutcome,
+    ConditionalTransferId,
+    ConditionalTransferRecord,
+    ErrorRecord,
+    FeeSamples,
+    HistoryEntry,
+    LatencyStats,
+    LedgerConfig,
+    LedgerStats,
+    OnDuplicate,
+    PortfolioValue,
+    QuarantineId,
+    QuarantineRule,
+    QuarantinedTransfer,
+    RecurringId,
+    RecurringTransfer,
+    ScheduleId,
+    ScheduledTransfer,
+    SignerQuorum,
+    SplitTransferReceipt,
+    SweepArg,
+    TimerInfo,
+    TokenPrice,
+    TrackingId,
+    TransferHistory,
+    TransferReceipt,
+    TransferStatus,
+    TransferToMultiple,
+    TransferSimulation,
+    TransferToPrincipal,
+    TreasuryConfig,
+};
+use api::queries::{ BudgetStatus, CallerStats, HistoryFilter, LifetimeOutflow };
+use candid::Principal;
 use ic_stable_structures::memory_manager::{ MemoryId, MemoryManager, VirtualMemory };
-use ic_stable_structures::{ DefaultMemoryImpl, StableBTreeMap };
+use ic_stable_structures::{ DefaultMemoryImpl, StableBTreeMap, StableCell };
 use std::cell::RefCell;
 
 pub mod api;
 
+// Bump together with a new arm in migrate_stable_memory whenever stored data needs
+// rewriting on upgrade.
+const CURRENT_SCHEMA_VERSION: u32 = 2;
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
     static TRANSFER_HISTORY: RefCell<
-        StableBTreeMap<u64, TransferHistory, VirtualMemory<DefaultMemoryImpl>>
+        StableBTreeMap<u64, HistoryEntry, VirtualMemory<DefaultMemoryImpl>>
     > = RefCell::new(
         StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
     );
+
+    static LEDGER_CONFIGS: RefCell<
+        StableBTreeMap<Principal, LedgerConfig, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    static RECURRING_TRANSFERS: RefCell<
+        StableBTreeMap<RecurringId, RecurringTransfer, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+
+    static SCHEDULED_TRANSFERS: RefCell<
+        StableBTreeMap<ScheduleId, ScheduledTransfer, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    static TREASURY_CONFIG: RefCell<
+        StableCell<TreasuryConfig, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            TreasuryConfig::default()
+        ).expect("failed to initialize treasury config")
+    );
+
+    static SAFE_RECIPIENT_CANISTERS: RefCell<
+        StableBTreeMap<Principal, (), VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+
+    static KNOWN_RECIPIENTS: RefCell<
+        StableBTreeMap<Principal, (), VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+
+    static QUARANTINED_TRANSFERS: RefCell<
+        StableBTreeMap<QuarantineId, QuarantinedTransfer, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))))
+    );
+
+    static PERMITTED_LEDGERS: RefCell<
+        StableBTreeMap<Principal, (), VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))))
+    );
+
+    static BUDGETS: RefCell<
+        StableBTreeMap<BudgetId, Budget, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+
+    static OBSERVERS: RefCell<
+        StableBTreeMap<Principal, (), VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))))
+    );
+
+    static BATCH_RUNS: RefCell<
+        StableBTreeMap<BatchRunId, BatchRun, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+
+    static FEE_HISTORY: RefCell<
+        StableBTreeMap<Principal, FeeSamples, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))))
+    );
+
+    static CLAIMS: RefCell<
+        StableBTreeMap<(Principal, Principal), Claim, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+
+    static CONDITIONAL_TRANSFERS: RefCell<
+        StableBTreeMap<ConditionalTransferId, ConditionalTransferRecord, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))))
+    );
+
+    static RECENT_ERRORS: RefCell<
+        StableBTreeMap<u64, ErrorRecord, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+
+    static LIFETIME_OUTFLOW: RefCell<
+        StableBTreeMap<Principal, u64, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))))
+    );
+
+    // Last heartbeat time per signer (controller), in nanoseconds.
+    static SIGNER_HEARTBEATS: RefCell<
+        StableBTreeMap<Principal, u64, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    );
+
+    // First history id of each archived range -> where the range went.
+    static HISTORY_ARCHIVES: RefCell<
+        StableBTreeMap<u64, ArchivedRange, VirtualMemory<DefaultMemoryImpl>>
+    > = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+
+    // Memory written before the version was tracked reads as version 1.
+    static SCHEMA_VERSION: RefCell<StableCell<u32, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), 1).expect(
+            "failed to initialize schema version"
+        )
+    );
 }
 
+pub fn schema_version() -> u32 {
+    SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_schema_version(version: u32) {
+    SCHEMA_VERSION.with(|cell| {
+        cell.borrow_mut().set(version).expect("failed to update schema version");
+    });
+}
+
+// Runs the migration out of every version between the stored one and
+// CURRENT_SCHEMA_VERSION in order, persisting the version after each step. Trapping
+// here rolls the upgrade back, which is what should happen for memory written by a
+// newer release.
+fn migrate_stable_memory() {
+    let mut version = schema_version();
+    if version > CURRENT_SCHEMA_VERSION {
+        ic_cdk::trap(
+            &format!(
+                "Stable memory schema version {} is newer than supported version {}",
+                version,
+                CURRENT_SCHEMA_VERSION
+            )
+        );
+    }
+
+    while version < CURRENT_SCHEMA_VERSION {
+        match version {
+            // v2 tracks known recipients, built from the existing history.
+            1 => api::updates::backfill_known_recipients(),
+            _ => ic_cdk::trap(&format!("No migration from schema version {}", version)),
+        }
+        version += 1;
+        set_schema_version(version);
+    }
+}
+
+#[ic_cdk::init]
+fn init() {
+    set_schema_version(CURRENT_SCHEMA_VERSION);
+    api::updates::start_fee_sampling();
+    api::updates::start_history_archiving();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_stable_memory();
+    api::updates::rearm_recurring_timers();
+    api::updates::rearm_scheduled_timers();
+    api::updates::start_fee_sampling();
+    api::updates::start_history_archiving();
+    // Only logged: failing the upgrade would leave the canister on the old code with the
+    // same jobs. Callers see the mismatches through verify_timers_rearmed and
+    // get_active_timers.
+    if !api::updates::timers_match_persisted_jobs() {
+        ic_cdk::println!("Not every persisted job has a timer after upgrade");
+    }
+}
 
-ic_cdk::export_candid!();
\ No newline at end of file
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::updates::PrincipalTransfer;
+    use candid::Encode;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    fn legacy_entry(transfer: TransferHistory) -> HistoryEntry {
+        HistoryEntry::from_bytes(Cow::Owned(Encode!(&transfer).unwrap()))
+    }
+
+    #[test]
+    fn migration_from_v1_backfills_known_recipients() {
+        let ledger_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+        let single = Principal::self_authenticating([1u8; 32]);
+        let first_leg = Principal::self_authenticating([2u8; 32]);
+        let second_leg = Principal::self_authenticating([3u8; 32]);
+        let never_paid = Principal::self_authenticating([4u8; 32]);
+
+        TRANSFER_HISTORY.with(|history| {
+            let mut history = history.borrow_mut();
+            history.insert(
+                1,
+                legacy_entry(
+                    TransferHistory::TransferToPrincipal(TransferToPrincipal {
+                        receiving_principal: single,
+                        amount: 10,
+                        ledger_id,
+                        category: None,
+                    })
+                )
+            );
+            history.insert(
+                2,
+                legacy_entry(
+                    TransferHistory::TransferToMultiple(TransferToMultiple {
+                        principals: vec![
+                            PrincipalTransfer { receiving_principal: first_leg, amount: 5 },
+                            PrincipalTransfer { receiving_principal: second_leg, amount: 6 }
+                        ],
+                        ledger_id,
+                        category: None,
+                    })
+                )
+            );
+        });
+        assert_eq!(schema_version(), 1);
+
+        migrate_stable_memory();
+
+        assert_eq!(schema_version(), CURRENT_SCHEMA_VERSION);
+        KNOWN_RECIPIENTS.with(|known| {
+            let known = known.borrow();
+            assert!(known.contains_key(&single));
+            assert!(known.contains_key(&first_leg));
+            assert!(known.contains_key(&second_leg));
+            assert!(!known.contains_key(&never_paid));
+        });
+
+        // Running it again on migrated memory is a no-op.
+        migrate_stable_memory();
+        assert_eq!(schema_version(), CURRENT_SCHEMA_VERSION);
+    }
+}